@@ -0,0 +1,130 @@
+// compares parsing a large upstream response against forwarding its bytes, run with `cargo bench`
+use std::{alloc::{GlobalAlloc, Layout, System}, sync::atomic::{AtomicUsize, Ordering}};
+use actix_web::web::Bytes;
+use criterion::{Criterion, Throughput, black_box, criterion_group, criterion_main};
+use serde_json::json;
+use svinge::common::{helper::{forwarded_body, inspect_forwarded}, types::{NumberString, RpcResponse}};
+
+// counts what the measured paths allocate
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+fn logs_body(count: usize) -> Bytes {
+    let logs = (0..count).map(|i| json!({
+        "address": "0x7a250d5630b4cf539739df2c5dacb4c659f2488d",
+        "topics": [
+            "0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef",
+            format!("0x{:064x}", i),
+            format!("0x{:064x}", i * 7)
+        ],
+        "data": format!("0x{:064x}", i * 1_000_000),
+        "blockNumber": format!("0x{:x}", 16_000_000 + i / 100),
+        "transactionHash": format!("0x{:064x}", i * 31),
+        "transactionIndex": format!("0x{:x}", i % 100),
+        "blockHash": format!("0x{:064x}", i / 100),
+        "logIndex": format!("0x{:x}", i),
+        "removed": false
+    })).collect::<Vec<_>>();
+
+    Bytes::from(json!({ "jsonrpc": "2.0", "id": 1, "result": logs }).to_string())
+}
+
+fn block_body(transactions: usize) -> Bytes {
+    let transactions = (0..transactions).map(|i| json!({
+        "hash": format!("0x{:064x}", i),
+        "from": format!("0x{:040x}", i),
+        "to": format!("0x{:040x}", i * 3),
+        "value": format!("0x{:x}", i * 1_000_000_000),
+        "gas": "0x5208",
+        "gasPrice": "0x4a817c800",
+        "nonce": format!("0x{:x}", i),
+        "input": format!("0xa9059cbb{:0>128}", i),
+        "transactionIndex": format!("0x{:x}", i),
+        "v": "0x25",
+        "r": format!("0x{:064x}", i * 5),
+        "s": format!("0x{:064x}", i * 11)
+    })).collect::<Vec<_>>();
+
+    Bytes::from(json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "result": {
+            "number": "0xf42400",
+            "hash": format!("0x{:064x}", 1),
+            "parentHash": format!("0x{:064x}", 0),
+            "miner": format!("0x{:040x}", 9),
+            "gasUsed": "0x1c9c380",
+            "timestamp": "0x63c3f5f7",
+            "transactions": transactions
+        }
+    }).to_string())
+}
+
+// what a parsed request does, the body is read into a response and written out again
+fn parse(body: &Bytes, id: &NumberString) -> String {
+    let response = serde_json::from_slice::<RpcResponse>(body).unwrap();
+
+    serde_json::to_string(&RpcResponse { id: id.clone(), ..response }).unwrap()
+}
+
+fn forward(body: &Bytes, id: &NumberString) -> Bytes {
+    inspect_forwarded(body).unwrap();
+    forwarded_body(body, id)
+}
+
+fn report_allocations(name: &str, run: impl Fn()) {
+    let (allocations, allocated) = (ALLOCATIONS.load(Ordering::Relaxed), ALLOCATED.load(Ordering::Relaxed));
+
+    run();
+
+    println!(
+        "{}: {} allocations, {} bytes",
+        name,
+        ALLOCATIONS.load(Ordering::Relaxed) - allocations,
+        ALLOCATED.load(Ordering::Relaxed) - allocated
+    );
+}
+
+fn bench_body(c: &mut Criterion, name: &str, body: Bytes) {
+    let same_id = NumberString::Number(1.into());
+    let other_id = NumberString::Number(2.into());
+
+    report_allocations(&format!("{}/parse", name), || { black_box(parse(&body, &same_id)); });
+    report_allocations(&format!("{}/forward", name), || { black_box(forward(&body, &same_id)); });
+    report_allocations(&format!("{}/forward_rewrite_id", name), || { black_box(forward(&body, &other_id)); });
+
+    let mut group = c.benchmark_group(name);
+    group.throughput(Throughput::Bytes(body.len() as u64));
+
+    group.bench_function("parse", |b| b.iter(|| parse(black_box(&body), &same_id)));
+    group.bench_function("forward", |b| b.iter(|| forward(black_box(&body), &same_id)));
+    // a coalesced request answered with the body of another one
+    group.bench_function("forward_rewrite_id", |b| b.iter(|| forward(black_box(&body), &other_id)));
+
+    group.finish();
+}
+
+fn benches(c: &mut Criterion) {
+    bench_body(c, "eth_getLogs", logs_body(10_000));
+    bench_body(c, "eth_getBlockByNumber", block_body(1_000));
+}
+
+criterion_group!(forwarding, benches);
+criterion_main!(forwarding);
@@ -0,0 +1,145 @@
+use serde_json::Value;
+
+use crate::common::types::RpcRequest;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockTag {
+    Latest,
+    Pending,
+    Safe,
+    Finalized,
+    Earliest,
+    Number(u64),
+    Hash
+}
+
+impl BlockTag {
+    pub fn from_request(request: &RpcRequest) -> Option<BlockTag> {
+        if is_block_hash_method(&request.method) {
+            return Some(BlockTag::Hash);
+        }
+
+        let position = block_tag_position(&request.method)?;
+
+        let params = match &request.params {
+            Value::Array(params) => params.as_slice(),
+            Value::Null => &[],
+            // named params, nothing to go by
+            _ => return None
+        };
+
+        match params.get(position) {
+            // an omitted block parameter means latest
+            None => Some(BlockTag::Latest),
+            Some(Value::String(tag)) => BlockTag::parse(tag),
+            // EIP-1898 block parameter
+            Some(Value::Object(object)) => {
+                if object.contains_key("blockHash") {
+                    return Some(BlockTag::Hash);
+                }
+
+                object.get("blockNumber").and_then(|number| number.as_str()).and_then(BlockTag::parse)
+            },
+            _ => None
+        }
+    }
+
+    pub fn parse(tag: &str) -> Option<BlockTag> {
+        match tag {
+            "latest" => Some(BlockTag::Latest),
+            "pending" => Some(BlockTag::Pending),
+            "safe" => Some(BlockTag::Safe),
+            "finalized" => Some(BlockTag::Finalized),
+            "earliest" => Some(BlockTag::Earliest),
+            hex if hex.len() == 66 && hex.starts_with("0x") => Some(BlockTag::Hash),
+            hex => u64::from_str_radix(hex.trim_start_matches("0x"), 16).ok().map(BlockTag::Number)
+        }
+    }
+}
+
+// position of the block parameter for EVM reads that take one
+pub fn block_tag_position(method: &str) -> Option<usize> {
+    match method {
+        "eth_getBlockByNumber"
+        | "eth_getBlockTransactionCountByNumber"
+        | "eth_getUncleCountByBlockNumber"
+        | "eth_getTransactionByBlockNumberAndIndex"
+        | "eth_getUncleByBlockNumberAndIndex"
+        | "eth_getBlockReceipts" => Some(0),
+        "eth_getBalance"
+        | "eth_getCode"
+        | "eth_getTransactionCount"
+        | "eth_call"
+        | "eth_estimateGas"
+        | "eth_feeHistory" => Some(1),
+        "eth_getStorageAt"
+        | "eth_getProof" => Some(2),
+        _ => None
+    }
+}
+
+fn is_block_hash_method(method: &str) -> bool {
+    matches!(
+        method,
+        "eth_getBlockByHash"
+            | "eth_getBlockTransactionCountByHash"
+            | "eth_getUncleCountByBlockHash"
+            | "eth_getTransactionByBlockHashAndIndex"
+            | "eth_getUncleByBlockHashAndIndex"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::common::types::NumberString;
+
+    fn request(method: &str, params: Value) -> RpcRequest {
+        RpcRequest { jsonrpc: "2.0".into(), method: method.into(), params, id: NumberString::Number(1.into()) }
+    }
+
+    #[test]
+    fn parses_tags_numbers_and_hashes() {
+        assert_eq!(BlockTag::parse("latest"), Some(BlockTag::Latest));
+        assert_eq!(BlockTag::parse("pending"), Some(BlockTag::Pending));
+        assert_eq!(BlockTag::parse("safe"), Some(BlockTag::Safe));
+        assert_eq!(BlockTag::parse("finalized"), Some(BlockTag::Finalized));
+        assert_eq!(BlockTag::parse("earliest"), Some(BlockTag::Earliest));
+        assert_eq!(BlockTag::parse("0x10"), Some(BlockTag::Number(16)));
+        assert_eq!(BlockTag::parse(&format!("0x{:064x}", 1)), Some(BlockTag::Hash));
+        assert_eq!(BlockTag::parse("0xffffffffffffffff"), Some(BlockTag::Number(u64::MAX)));
+        assert_eq!(BlockTag::parse("0x1ffffffffffffffff"), None);
+        assert_eq!(BlockTag::parse("soon"), None);
+    }
+
+    #[test]
+    fn finds_the_block_parameter() {
+        assert_eq!(BlockTag::from_request(&request("eth_getBlockByNumber", json!(["0x1", false]))), Some(BlockTag::Number(1)));
+        assert_eq!(BlockTag::from_request(&request("eth_call", json!([{ "to": "0x0" }, "pending"]))), Some(BlockTag::Pending));
+        assert_eq!(BlockTag::from_request(&request("eth_getStorageAt", json!(["0x0", "0x0", "safe"]))), Some(BlockTag::Safe));
+        assert_eq!(BlockTag::from_request(&request("eth_getBlockByHash", json!(["0x0", false]))), Some(BlockTag::Hash));
+    }
+
+    #[test]
+    fn omitted_block_parameter_is_latest() {
+        assert_eq!(BlockTag::from_request(&request("eth_getBalance", json!(["0x0"]))), Some(BlockTag::Latest));
+        assert_eq!(BlockTag::from_request(&request("eth_getBlockByNumber", Value::Null)), Some(BlockTag::Latest));
+    }
+
+    #[test]
+    fn reads_eip_1898_objects() {
+        let hash = format!("0x{:064x}", 1);
+
+        assert_eq!(BlockTag::from_request(&request("eth_call", json!([{}, { "blockHash": hash }]))), Some(BlockTag::Hash));
+        assert_eq!(BlockTag::from_request(&request("eth_call", json!([{}, { "blockNumber": "0x2" }]))), Some(BlockTag::Number(2)));
+    }
+
+    #[test]
+    fn nothing_to_go_by() {
+        assert_eq!(BlockTag::from_request(&request("eth_getLogs", json!([{}]))), None);
+        assert_eq!(BlockTag::from_request(&request("eth_getBalance", json!({ "address": "0x0" }))), None);
+        assert_eq!(BlockTag::from_request(&request("eth_getBalance", json!(["0x0", 5]))), None);
+    }
+}
@@ -0,0 +1,716 @@
+use std::{collections::{BTreeMap, HashMap, hash_map::DefaultHasher}, fmt::Debug, fs::{self, File}, hash::{Hash, Hasher}, io::{self, Write}, path::PathBuf, sync::{Arc, Mutex}, time::{Duration, Instant, SystemTime}};
+use futures::{FutureExt, future::{self, BoxFuture}};
+use serde::{Serialize, Deserialize};
+use log::warn;
+
+use crate::common::types::Response;
+
+// where a chain keeps the responses it can serve again, a failing backend only costs cache misses
+pub trait ResponseCache: Debug + Send + Sync {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Option<Response>>;
+
+    // `ttl` is how long the response can be served, None when it never goes stale
+    fn put<'a>(&'a self, key: &'a str, response: &'a Response, ttl: Option<Duration>) -> BoxFuture<'a, ()>;
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheStoreOptions {
+    #[serde(default)]
+    pub backend: CacheBackend,
+    // limits of the memory and disk backends, the least recently used responses go first in memory,
+    // the oldest written ones on disk
+    #[serde(default = "default_max_entries")]
+    pub max_entries: usize,
+    #[serde(default = "default_max_bytes")]
+    pub max_bytes: usize,
+    // directory of the disk backend, every chain gets its own folder in it
+    #[serde(default = "default_path")]
+    pub path: PathBuf,
+    // address of the redis backend, anything speaking the redis protocol works
+    #[serde(default = "default_url")]
+    pub url: String,
+    // milliseconds a redis call can take, a slow or unreachable server only costs cache misses
+    #[serde(default = "default_timeout")]
+    pub timeout: u64
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheBackend {
+    #[default]
+    Memory,
+    Disk,
+    // shared by every replica pointing at the same server
+    Redis
+}
+
+fn default_max_entries() -> usize {
+    10000
+}
+
+fn default_max_bytes() -> usize {
+    64 * 1024 * 1024
+}
+
+fn default_path() -> PathBuf {
+    PathBuf::from("/tmp/svinge-cache")
+}
+
+fn default_url() -> String {
+    "redis://127.0.0.1:6379".into()
+}
+
+fn default_timeout() -> u64 {
+    500
+}
+
+impl Default for CacheStoreOptions {
+    fn default() -> CacheStoreOptions {
+        CacheStoreOptions {
+            backend: CacheBackend::default(),
+            max_entries: default_max_entries(),
+            max_bytes: default_max_bytes(),
+            path: default_path(),
+            url: default_url(),
+            timeout: default_timeout()
+        }
+    }
+}
+
+impl CacheStoreOptions {
+    pub async fn response_cache(&self, chain_id: &str) -> io::Result<Box<dyn ResponseCache>> {
+        match self.backend {
+            CacheBackend::Memory => Ok(Box::new(LruCache::new(self.max_entries, self.max_bytes))),
+            CacheBackend::Disk => Ok(Box::new(DiskCache::open(self.path.join(chain_id), self.max_entries, self.max_bytes)?)),
+            CacheBackend::Redis => Ok(Box::new(RedisCache::connect(&self.url, chain_id, Duration::from_millis(self.timeout)).await?))
+        }
+    }
+}
+
+#[derive(Debug)]
+struct LruEntry {
+    response: Response,
+    size: usize,
+    // position in `order`, bumped on every hit
+    tick: u64
+}
+
+#[derive(Debug, Default)]
+struct LruState {
+    entries: HashMap<String, LruEntry>,
+    // keys from the least to the most recently used
+    order: BTreeMap<u64, String>,
+    tick: u64,
+    bytes: usize
+}
+
+#[derive(Debug)]
+pub struct LruCache {
+    max_entries: usize,
+    max_bytes: usize,
+    state: Mutex<LruState>
+}
+
+impl LruCache {
+    pub fn new(max_entries: usize, max_bytes: usize) -> LruCache {
+        LruCache { max_entries, max_bytes, state: Mutex::new(LruState::default()) }
+    }
+
+    fn get_sync(&self, key: &str) -> Option<Response> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+
+        state.tick += 1;
+
+        let entry = state.entries.get_mut(key)?;
+
+        state.order.remove(&entry.tick);
+        state.order.insert(state.tick, key.into());
+        entry.tick = state.tick;
+
+        Some(entry.response.clone())
+    }
+
+    fn put_sync(&self, key: &str, response: &Response) {
+        // roughly what the response takes, good enough to bound memory, a forwarded one comes with the bytes
+        // the upstream sent so it isn't serialized again to be measured
+        let size = key.len() + match &response.body {
+            Some(body) => body.len(),
+            None => serde_json::to_vec(response).map(|bytes| bytes.len()).unwrap_or(0)
+        };
+
+        if size > self.max_bytes || self.max_entries == 0 {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+
+        state.tick += 1;
+
+        let tick = state.tick;
+
+        // the parsed result is what is served from now on, the raw body would only double the memory taken
+        let response = Response { body: None, ..response.clone() };
+
+        if let Some(previous) = state.entries.insert(key.into(), LruEntry { response, size, tick }) {
+            state.order.remove(&previous.tick);
+            state.bytes -= previous.size;
+        }
+
+        state.order.insert(tick, key.into());
+        state.bytes += size;
+
+        while state.entries.len() > self.max_entries || state.bytes > self.max_bytes {
+            let (_, oldest) = match state.order.pop_first() {
+                Some(oldest) => oldest,
+                None => break
+            };
+
+            if let Some(evicted) = state.entries.remove(&oldest) {
+                state.bytes -= evicted.size;
+            }
+        }
+    }
+}
+
+impl ResponseCache for LruCache {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Option<Response>> {
+        future::ready(self.get_sync(key)).boxed()
+    }
+
+    fn put<'a>(&'a self, key: &'a str, response: &'a Response, _ttl: Option<Duration>) -> BoxFuture<'a, ()> {
+        self.put_sync(key, response);
+        future::ready(()).boxed()
+    }
+}
+
+// one json file per response, named after a hash of its key
+#[derive(Debug)]
+pub struct DiskCache {
+    dir: PathBuf,
+    max_entries: usize,
+    max_bytes: usize,
+    index: Arc<Mutex<DiskIndex>>
+}
+
+#[derive(Serialize, Deserialize)]
+struct DiskEntry {
+    // kept to tell apart keys whose hashes collide
+    key: String,
+    response: Response,
+    // None when the response never goes stale
+    #[serde(default)]
+    expires_at: Option<SystemTime>
+}
+
+// files of the cache from the oldest to the latest written, what is dropped first once it is full
+#[derive(Debug, Default)]
+struct DiskIndex {
+    files: HashMap<PathBuf, (usize, u64)>,
+    order: BTreeMap<u64, PathBuf>,
+    tick: u64,
+    bytes: usize
+}
+
+impl DiskIndex {
+    fn insert(&mut self, path: PathBuf, size: usize) {
+        self.remove(&path);
+
+        self.tick += 1;
+        self.order.insert(self.tick, path.clone());
+        self.files.insert(path, (size, self.tick));
+        self.bytes += size;
+    }
+
+    fn remove(&mut self, path: &PathBuf) {
+        if let Some((size, tick)) = self.files.remove(path) {
+            self.order.remove(&tick);
+            self.bytes -= size;
+        }
+    }
+
+    // files to delete to get back under the limits
+    fn evict(&mut self, max_entries: usize, max_bytes: usize) -> Vec<PathBuf> {
+        let mut evicted = vec![];
+
+        while self.files.len() > max_entries || self.bytes > max_bytes {
+            let (_, oldest) = match self.order.pop_first() {
+                Some(oldest) => oldest,
+                None => break
+            };
+
+            if let Some((size, _)) = self.files.remove(&oldest) {
+                self.bytes -= size;
+            }
+
+            evicted.push(oldest);
+        }
+
+        evicted
+    }
+}
+
+impl DiskCache {
+    // picks up the responses left by a previous run, the oldest of them go first when over the limits
+    pub fn open(dir: PathBuf, max_entries: usize, max_bytes: usize) -> io::Result<DiskCache> {
+        fs::create_dir_all(&dir)?;
+
+        let mut files = vec![];
+
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+
+            if entry.path().extension().map(|extension| extension == "json").unwrap_or(false) {
+                files.push((metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH), entry.path(), metadata.len() as usize));
+            }
+        }
+
+        files.sort();
+
+        let mut index = DiskIndex::default();
+
+        for (_, path, size) in files {
+            index.insert(path, size);
+        }
+
+        for path in index.evict(max_entries, max_bytes) {
+            let _ = fs::remove_file(path);
+        }
+
+        Ok(DiskCache { dir, max_entries, max_bytes, index: Arc::new(Mutex::new(index)) })
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+
+        self.dir.join(format!("{:016x}.json", hasher.finish()))
+    }
+
+    // a stale response is deleted on the way
+    fn read(path: PathBuf, key: &str, index: &Mutex<DiskIndex>) -> io::Result<Option<Response>> {
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err)
+        };
+
+        let entry = serde_json::from_slice::<DiskEntry>(&bytes)?;
+
+        if entry.expires_at.map(|expires_at| expires_at <= SystemTime::now()).unwrap_or(false) {
+            index.lock().unwrap().remove(&path);
+            fs::remove_file(path)?;
+
+            return Ok(None);
+        }
+
+        Ok(Some(entry.response).filter(|_| entry.key == key))
+    }
+
+    // written next to the target and renamed over it, so readers never see half a response
+    fn write(path: PathBuf, bytes: &[u8]) -> io::Result<()> {
+        let tmp = path.with_extension("json.tmp");
+
+        let mut file = File::create(&tmp)?;
+        file.write_all(bytes)?;
+
+        fs::rename(tmp, path)
+    }
+
+    fn store(path: PathBuf, bytes: &[u8], index: &Mutex<DiskIndex>, max_entries: usize, max_bytes: usize) -> io::Result<()> {
+        DiskCache::write(path.clone(), bytes)?;
+
+        let evicted = {
+            let mut index = index.lock().unwrap();
+
+            index.insert(path, bytes.len());
+            index.evict(max_entries, max_bytes)
+        };
+
+        for path in evicted {
+            fs::remove_file(path)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl ResponseCache for DiskCache {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Option<Response>> {
+        let path = self.path(key);
+        let key = key.to_string();
+        let index = self.index.clone();
+
+        async move {
+            match tokio::task::spawn_blocking(move || DiskCache::read(path, &key, &index)).await {
+                Ok(Ok(response)) => response,
+                Ok(Err(err)) => {
+                    warn!("Could not read a cached response: {}", err);
+                    None
+                },
+                Err(_) => None
+            }
+        }.boxed()
+    }
+
+    fn put<'a>(&'a self, key: &'a str, response: &'a Response, ttl: Option<Duration>) -> BoxFuture<'a, ()> {
+        let path = self.path(key);
+        let expires_at = ttl.map(|ttl| SystemTime::now() + ttl);
+        let bytes = serde_json::to_vec(&DiskEntry { key: key.into(), response: response.clone(), expires_at });
+        let (index, max_entries, max_bytes) = (self.index.clone(), self.max_entries, self.max_bytes);
+
+        async move {
+            let result = match bytes {
+                // a response over the limits on its own would only push everything else out
+                Ok(bytes) if bytes.len() > max_bytes || max_entries == 0 => Ok(()),
+                Ok(bytes) => tokio::task::spawn_blocking(move || DiskCache::store(path, &bytes, &index, max_entries, max_bytes)).await.unwrap_or_else(|err| Err(io::Error::other(err))),
+                Err(err) => Err(err.into())
+            };
+
+            if let Err(err) = result {
+                warn!("Could not write a cached response: {}", err);
+            }
+        }.boxed()
+    }
+}
+
+// milliseconds between two attempts to reach a redis server that was down
+const REDIS_RECONNECT_DELAY: u64 = 5000;
+
+pub struct RedisCache {
+    client: redis::Client,
+    // None until the server could be reached, requests are cache misses in the meantime
+    connection: Mutex<Option<redis::aio::ConnectionManager>>,
+    // no new connection is tried before then after a failed one
+    retry_at: Mutex<Instant>,
+    timeout: Duration,
+    // keys are prefixed with it so chains sharing a server don't mix
+    prefix: String
+}
+
+impl Debug for RedisCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisCache").field("prefix", &self.prefix).finish()
+    }
+}
+
+impl RedisCache {
+    // only a malformed url fails, a server that can't be reached yet is connected to later
+    pub async fn connect(url: &str, chain_id: &str, timeout: Duration) -> io::Result<RedisCache> {
+        let client = redis::Client::open(url).map_err(io::Error::other)?;
+
+        let cache = RedisCache {
+            client,
+            connection: Mutex::new(None),
+            retry_at: Mutex::new(Instant::now()),
+            timeout,
+            prefix: format!("svinge:{}:", chain_id)
+        };
+
+        if cache.connection().await.is_none() {
+            warn!("Redis at {} is unreachable, responses of chain {} are not cached until it is back", url, chain_id);
+        }
+
+        Ok(cache)
+    }
+
+    // the connection manager reconnects by itself once it was set up, only the first connection is retried here
+    async fn connection(&self) -> Option<redis::aio::ConnectionManager> {
+        if let Some(connection) = self.connection.lock().unwrap().clone() {
+            return Some(connection);
+        }
+
+        {
+            let mut retry_at = self.retry_at.lock().unwrap();
+
+            if Instant::now() < *retry_at {
+                return None;
+            }
+
+            *retry_at = Instant::now() + Duration::from_millis(REDIS_RECONNECT_DELAY);
+        }
+
+        match tokio::time::timeout(self.timeout, self.client.get_tokio_connection_manager()).await {
+            Ok(Ok(connection)) => {
+                *self.connection.lock().unwrap() = Some(connection.clone());
+                Some(connection)
+            },
+            Ok(Err(err)) => {
+                warn!("Could not connect to redis: {}", err);
+                None
+            },
+            Err(_) => {
+                warn!("Connecting to redis timed out");
+                None
+            }
+        }
+    }
+
+    async fn query<T: redis::FromRedisValue>(&self, command: &redis::Cmd) -> Result<T, String> {
+        let mut connection = self.connection().await.ok_or_else(|| "not connected".to_string())?;
+
+        match tokio::time::timeout(self.timeout, command.query_async::<_, T>(&mut connection)).await {
+            Ok(result) => result.map_err(|err| err.to_string()),
+            Err(_) => Err(format!("no answer within {} ms", self.timeout.as_millis()))
+        }
+    }
+}
+
+impl ResponseCache for RedisCache {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, Option<Response>> {
+        async move {
+            let mut command = redis::cmd("GET");
+            command.arg(format!("{}{}", self.prefix, key));
+
+            match self.query::<Option<Vec<u8>>>(&command).await {
+                Ok(bytes) => bytes.and_then(|bytes| serde_json::from_slice(&bytes).ok()),
+                Err(err) => {
+                    warn!("Could not read a cached response from redis: {}", err);
+                    None
+                }
+            }
+        }.boxed()
+    }
+
+    fn put<'a>(&'a self, key: &'a str, response: &'a Response, ttl: Option<Duration>) -> BoxFuture<'a, ()> {
+        async move {
+            let bytes = match serde_json::to_vec(response) {
+                Ok(bytes) => bytes,
+                Err(_) => return
+            };
+
+            let mut command = redis::cmd("SET");
+            command.arg(format!("{}{}", self.prefix, key)).arg(bytes);
+
+            // redis drops stale responses by itself
+            if let Some(ttl) = ttl {
+                command.arg("PX").arg(ttl.as_millis().max(1) as u64);
+            }
+
+            if let Err(err) = self.query::<()>(&command).await {
+                warn!("Could not write a cached response to redis: {}", err);
+            }
+        }.boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use actix_web::web::Bytes;
+    use serde_json::{Value, json};
+    use tokio::{io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader}, net::TcpListener};
+
+    use super::*;
+
+    fn response(result: Value) -> Response {
+        Response { method: "eth_call".into(), params: json!([]), result: Some(result), time_taken: 1, start_time: SystemTime::now(), error: None, body: None }
+    }
+
+    fn size(key: &str, response: &Response) -> usize {
+        key.len() + serde_json::to_vec(response).unwrap().len()
+    }
+
+    #[test]
+    fn lru_evicts_least_recently_used_past_max_entries() {
+        let cache = LruCache::new(2, usize::MAX);
+
+        cache.put_sync("a", &response(json!(1)));
+        cache.put_sync("b", &response(json!(2)));
+        // a hit makes "a" the most recently used, "b" goes first
+        assert!(cache.get_sync("a").is_some());
+        cache.put_sync("c", &response(json!(3)));
+
+        assert!(cache.get_sync("a").is_some());
+        assert!(cache.get_sync("b").is_none());
+        assert!(cache.get_sync("c").is_some());
+    }
+
+    #[test]
+    fn lru_evicts_past_max_bytes() {
+        let entry = size("a", &response(json!(1)));
+        let cache = LruCache::new(100, entry * 2);
+
+        cache.put_sync("a", &response(json!(1)));
+        cache.put_sync("b", &response(json!(2)));
+        cache.put_sync("c", &response(json!(3)));
+
+        assert!(cache.get_sync("a").is_none());
+        assert!(cache.get_sync("b").is_some());
+        assert!(cache.get_sync("c").is_some());
+        assert_eq!(cache.state.lock().unwrap().bytes, entry * 2);
+    }
+
+    #[test]
+    fn lru_skips_responses_larger_than_max_bytes() {
+        let cache = LruCache::new(100, 10);
+
+        cache.put_sync("a", &response(json!("a response longer than ten bytes")));
+
+        assert!(cache.get_sync("a").is_none());
+        assert_eq!(cache.state.lock().unwrap().bytes, 0);
+    }
+
+    fn disk_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("svinge-cache-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        dir
+    }
+
+    #[tokio::test]
+    async fn disk_drops_oldest_past_max_entries() {
+        let dir = disk_dir("entries");
+        let cache = DiskCache::open(dir.clone(), 2, usize::MAX).unwrap();
+
+        for (i, key) in ["a", "b", "c"].iter().enumerate() {
+            cache.put(key, &response(json!(i)), None).await;
+            // a restart orders files by modification time, which can be coarse
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        assert!(cache.get("a").await.is_none());
+        assert_eq!(cache.get("c").await.unwrap().result, Some(json!(2)));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        // a restart picks up the files left behind and keeps the limits
+        let cache = DiskCache::open(dir.clone(), 1, usize::MAX).unwrap();
+
+        assert!(cache.get("b").await.is_none());
+        assert!(cache.get("c").await.is_some());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn disk_drops_expired_responses() {
+        let dir = disk_dir("ttl");
+        let cache = DiskCache::open(dir.clone(), 10, usize::MAX).unwrap();
+
+        cache.put("stale", &response(json!(1)), Some(Duration::from_millis(1))).await;
+        cache.put("forever", &response(json!(2)), None).await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        assert!(cache.get("stale").await.is_none());
+        assert!(cache.get("forever").await.is_some());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // values set on the stand-in along with their PX argument
+    type RedisStore = Arc<Mutex<HashMap<String, (Vec<u8>, Option<String>)>>>;
+
+    // answers GET and SET like a redis server would
+    async fn redis_stand_in(store: RedisStore) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("redis://{}", listener.local_addr().unwrap());
+
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                let store = store.clone();
+
+                tokio::spawn(async move {
+                    let (reader, mut writer) = socket.into_split();
+                    let mut reader = BufReader::new(reader);
+                    let mut line = String::new();
+
+                    while reader.read_line(&mut line).await.unwrap_or(0) > 0 {
+                        let count = line.trim_start_matches('*').trim().parse::<usize>().unwrap();
+                        let mut args = vec![];
+
+                        for _ in 0..count {
+                            line.clear();
+                            reader.read_line(&mut line).await.unwrap();
+
+                            let mut arg = vec![0; line.trim_start_matches('$').trim().parse::<usize>().unwrap() + 2];
+                            reader.read_exact(&mut arg).await.unwrap();
+                            arg.truncate(arg.len() - 2);
+                            args.push(arg);
+                        }
+
+                        let reply = match args[0].as_slice() {
+                            b"SET" => {
+                                let px = args.get(4).map(|px| String::from_utf8(px.clone()).unwrap());
+                                store.lock().unwrap().insert(String::from_utf8(args[1].clone()).unwrap(), (args[2].clone(), px));
+                                b"+OK\r\n".to_vec()
+                            },
+                            b"GET" => match store.lock().unwrap().get(&String::from_utf8(args[1].clone()).unwrap()) {
+                                Some((value, _)) => [format!("${}\r\n", value.len()).into_bytes(), value.clone(), b"\r\n".to_vec()].concat(),
+                                None => b"$-1\r\n".to_vec()
+                            },
+                            _ => b"-ERR unknown command\r\n".to_vec()
+                        };
+
+                        writer.write_all(&reply).await.unwrap();
+                        line.clear();
+                    }
+                });
+            }
+        });
+
+        url
+    }
+
+    #[tokio::test]
+    async fn redis_stores_with_prefix_and_ttl() {
+        let store = Arc::new(Mutex::new(HashMap::new()));
+        let url = redis_stand_in(store.clone()).await;
+        let cache = RedisCache::connect(&url, "5", Duration::from_millis(500)).await.unwrap();
+
+        assert!(cache.get("eth_call:[]").await.is_none());
+
+        cache.put("eth_call:[]", &response(json!("0x1")), Some(Duration::from_secs(2))).await;
+        cache.put("eth_chainId:[]", &response(json!("0x5")), None).await;
+
+        assert_eq!(cache.get("eth_call:[]").await.unwrap().result, Some(json!("0x1")));
+
+        let store = store.lock().unwrap();
+        assert_eq!(store["svinge:5:eth_call:[]"].1.as_deref(), Some("2000"));
+        assert_eq!(store["svinge:5:eth_chainId:[]"].1, None);
+    }
+
+    #[tokio::test]
+    async fn redis_down_at_startup_only_misses() {
+        // a port nobody listens on anymore
+        let url = format!("redis://{}", TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap());
+        let cache = RedisCache::connect(&url, "5", Duration::from_millis(500)).await.unwrap();
+
+        cache.put("eth_call:[]", &response(json!("0x1")), None).await;
+
+        assert!(cache.get("eth_call:[]").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn redis_calls_time_out() {
+        // accepts connections but never answers
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("redis://{}", listener.local_addr().unwrap());
+
+        tokio::spawn(async move {
+            let mut sockets = vec![];
+
+            while let Ok((socket, _)) = listener.accept().await {
+                sockets.push(socket);
+            }
+        });
+
+        let cache = RedisCache::connect(&url, "5", Duration::from_millis(100)).await.unwrap();
+        let started = Instant::now();
+
+        assert!(cache.get("eth_call:[]").await.is_none());
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn lru_measures_forwarded_responses_by_their_body() {
+        let cache = LruCache::new(100, usize::MAX);
+        let body = Bytes::from_static(br#"{"jsonrpc":"2.0","id":1,"result":"0x1"}"#);
+
+        cache.put_sync("a", &Response { body: Some(body.clone()), ..response(json!("0x1")) });
+
+        assert_eq!(cache.state.lock().unwrap().bytes, 1 + body.len());
+        assert!(cache.get_sync("a").unwrap().body.is_none());
+    }
+}
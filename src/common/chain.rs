@@ -0,0 +1,263 @@
+use std::fmt::Debug;
+use serde_json::Value;
+
+use crate::common::{
+    block_tag::BlockTag,
+    commitment::{Commitment, is_immutable_lookup},
+    health::Health,
+    types::{Blockchain, CacheOptions, CachePolicy, CacheRule, RpcRequest}
+};
+
+// what differs between chain families, adding one is a matter of adding an adapter
+pub trait ChainAdapter: Debug + Send + Sync {
+    // method whose answer tells which chain an upstream serves
+    fn identity_method(&self) -> &'static str;
+
+    // checks the answer to `identity_method` against the configured chain id
+    fn verify_identity(&self, chain_id: &str, result: &Option<Value>) -> Result<(), String>;
+
+    // method returning the head the upstream is at, used to spot lagging ones
+    fn head_method(&self) -> &'static str;
+
+    fn parse_head(&self, result: &Option<Value>) -> Option<u64>;
+
+    // methods the health checker sends when the config doesn't list any
+    fn default_probes(&self) -> Vec<String>;
+
+    // judges the answer to a probe that is neither the identity nor the head method
+    fn inspect_probe(&self, _method: &str, _result: &Option<Value>, _health: &mut Health) {}
+
+    // methods with side effects, never cached or coalesced
+    fn is_write_method(&self, method: &str) -> bool;
+
+    // rule for a method the config doesn't mention, None to use `cache_clear`
+    fn default_rule(&self, method: &str) -> Option<CacheRule>;
+
+    // rule depending on the params of the request, like the block it reads at, None to go by method
+    fn request_rule(&self, _request: &RpcRequest, _options: &CacheOptions, _latest_block: u64) -> Option<CacheRule> {
+        None
+    }
+
+    // whether upstreams take eth_subscribe over their websocket, chains without it get no subscription hub
+    fn supports_subscriptions(&self) -> bool {
+        false
+    }
+}
+
+impl Blockchain {
+    pub fn adapter(&self) -> &'static dyn ChainAdapter {
+        match self {
+            Blockchain::Ethereum | Blockchain::Evm => &EvmAdapter,
+            Blockchain::Solana => &SolanaAdapter
+        }
+    }
+}
+
+fn parse_hex(value: &Option<Value>) -> Option<u64> {
+    u64::from_str_radix(value.as_ref()?.as_str()?.trim_start_matches("0x"), 16).ok()
+}
+
+fn mismatch(chain_id: &str, probed_chain_id: Option<String>) -> Result<(), String> {
+    if probed_chain_id.as_deref() == Some(chain_id) {
+        return Ok(());
+    }
+
+    Err(format!("{} is not equal to RPCs response {:?}", chain_id, probed_chain_id))
+}
+
+// Ethereum and every chain speaking its JSON-RPC, identified by their decimal chain id
+#[derive(Debug)]
+pub struct EvmAdapter;
+
+impl ChainAdapter for EvmAdapter {
+    fn identity_method(&self) -> &'static str {
+        "eth_chainId"
+    }
+
+    fn verify_identity(&self, chain_id: &str, result: &Option<Value>) -> Result<(), String> {
+        mismatch(chain_id, parse_hex(result).map(|chain_id| chain_id.to_string()))
+    }
+
+    fn head_method(&self) -> &'static str {
+        "eth_blockNumber"
+    }
+
+    fn parse_head(&self, result: &Option<Value>) -> Option<u64> {
+        parse_hex(result)
+    }
+
+    fn default_probes(&self) -> Vec<String> {
+        vec!["eth_chainId".into(), "eth_blockNumber".into(), "eth_syncing".into()]
+    }
+
+    fn inspect_probe(&self, method: &str, result: &Option<Value>, health: &mut Health) {
+        // false when in sync, an object with the sync progress otherwise
+        if method == "eth_syncing" && !matches!(result, Some(Value::Bool(false))) {
+            health.healthy = false;
+            health.syncing = true;
+            health.error = Some("upstream is syncing".into());
+        }
+    }
+
+    // filters live on the upstream that created them, their changes are consumed by reading them
+    fn is_write_method(&self, method: &str) -> bool {
+        matches!(
+            method,
+            "eth_sendRawTransaction" | "eth_sendTransaction" | "eth_sign" | "eth_signTransaction" | "personal_sign"
+                | "eth_newFilter" | "eth_newBlockFilter" | "eth_newPendingTransactionFilter" | "eth_getFilterChanges" | "eth_uninstallFilter"
+        ) || method.starts_with("eth_signTypedData")
+    }
+
+    fn default_rule(&self, method: &str) -> Option<CacheRule> {
+        match method {
+            "eth_chainId" | "net_version" => Some(CacheRule::Policy(CachePolicy::Forever)),
+            // a new block comes every few seconds, a second old head is still useful
+            "eth_blockNumber" => Some(CacheRule::Ttl(1_000_000)),
+            "eth_getTransactionReceipt" | "eth_getTransactionByHash" => Some(CacheRule::Policy(CachePolicy::ForeverIfNotNull)),
+            method if self.is_write_method(method) => Some(CacheRule::Policy(CachePolicy::Never)),
+            _ => None
+        }
+    }
+
+    fn supports_subscriptions(&self) -> bool {
+        true
+    }
+
+    // reads pinned to a block hash or a final block are kept forever, head relative reads expire after `head_ttl`
+    fn request_rule(&self, request: &RpcRequest, options: &CacheOptions, latest_block: u64) -> Option<CacheRule> {
+        match BlockTag::from_request(request)? {
+            BlockTag::Hash | BlockTag::Earliest => Some(CacheRule::Policy(CachePolicy::ForeverIfNotNull)),
+            BlockTag::Number(number) if number <= latest_block && latest_block.saturating_sub(number) >= options.finality_depth => Some(CacheRule::Policy(CachePolicy::ForeverIfNotNull)),
+            BlockTag::Pending => Some(CacheRule::Policy(CachePolicy::Never)),
+            _ => Some(CacheRule::Ttl(options.head_ttl()))
+        }
+    }
+}
+
+// identified by their genesis hash, slots stand in for block numbers
+#[derive(Debug)]
+pub struct SolanaAdapter;
+
+impl ChainAdapter for SolanaAdapter {
+    fn identity_method(&self) -> &'static str {
+        "getGenesisHash"
+    }
+
+    fn verify_identity(&self, chain_id: &str, result: &Option<Value>) -> Result<(), String> {
+        mismatch(chain_id, result.as_ref().and_then(Value::as_str).map(String::from))
+    }
+
+    fn head_method(&self) -> &'static str {
+        "getSlot"
+    }
+
+    fn parse_head(&self, result: &Option<Value>) -> Option<u64> {
+        result.as_ref()?.as_u64()
+    }
+
+    fn default_probes(&self) -> Vec<String> {
+        vec!["getGenesisHash".into(), "getHealth".into(), "getSlot".into()]
+    }
+
+    fn inspect_probe(&self, method: &str, result: &Option<Value>, health: &mut Health) {
+        // "ok" when the node is close enough to the cluster, an error otherwise
+        if method == "getHealth" && result.as_ref().and_then(Value::as_str) != Some("ok") {
+            health.healthy = false;
+            health.error = Some(format!("getHealth: {:?}", result));
+        }
+    }
+
+    fn is_write_method(&self, method: &str) -> bool {
+        matches!(method, "sendTransaction" | "requestAirdrop")
+    }
+
+    fn default_rule(&self, method: &str) -> Option<CacheRule> {
+        match method {
+            "getGenesisHash" => Some(CacheRule::Policy(CachePolicy::Forever)),
+            method if self.is_write_method(method) => Some(CacheRule::Policy(CachePolicy::Never)),
+            _ => None
+        }
+    }
+
+    // processed state can change any time, finalized blocks and transactions never do
+    fn request_rule(&self, request: &RpcRequest, options: &CacheOptions, _latest_block: u64) -> Option<CacheRule> {
+        match Commitment::from_request(request) {
+            Commitment::Processed => Some(CacheRule::Policy(CachePolicy::Never)),
+            Commitment::Finalized if is_immutable_lookup(&request.method) => Some(CacheRule::Policy(CachePolicy::ForeverIfNotNull)),
+            _ => Some(CacheRule::Ttl(options.head_ttl()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::common::types::NumberString;
+
+    fn options() -> CacheOptions {
+        serde_json::from_value(json!({ "cache_clear": 2000000, "exclude_methods": [], "finality_depth": 64 })).unwrap()
+    }
+
+    fn rule(block: &str, latest_block: u64) -> Option<CacheRule> {
+        let request = RpcRequest { jsonrpc: "2.0".into(), method: "eth_getBalance".into(), params: json!(["0x0", block]), id: NumberString::Number(1.into()) };
+
+        EvmAdapter.request_rule(&request, &options(), latest_block)
+    }
+
+    #[test]
+    fn final_blocks_are_kept_forever() {
+        let forever = Some(CacheRule::Policy(CachePolicy::ForeverIfNotNull));
+        let head = Some(CacheRule::Ttl(2000000));
+
+        assert_eq!(rule("0x64", 164), forever);
+        assert_eq!(rule("0x64", 163), head);
+        // ahead of the head seen so far, or while it is still unknown
+        assert_eq!(rule("0x64", 50), head);
+        assert_eq!(rule("0x64", 0), head);
+    }
+
+    #[test]
+    fn huge_block_numbers_do_not_overflow() {
+        assert_eq!(rule("0xffffffffffffffff", 100), Some(CacheRule::Ttl(2000000)));
+        assert_eq!(rule("0xffffffffffffffff", u64::MAX), Some(CacheRule::Ttl(2000000)));
+    }
+
+    fn solana_request(method: &str, params: serde_json::Value) -> RpcRequest {
+        RpcRequest { jsonrpc: "2.0".into(), method: method.into(), params, id: NumberString::Number(1.into()) }
+    }
+
+    #[test]
+    fn solana_identity_is_the_genesis_hash() {
+        assert!(SolanaAdapter.verify_identity("genesis", &Some(json!("genesis"))).is_ok());
+        assert!(SolanaAdapter.verify_identity("genesis", &Some(json!("other"))).is_err());
+        assert!(SolanaAdapter.verify_identity("genesis", &None).is_err());
+        assert!(!SolanaAdapter.supports_subscriptions());
+        assert!(EvmAdapter.supports_subscriptions());
+    }
+
+    #[test]
+    fn solana_rules_follow_the_commitment() {
+        let rule = |method: &str, params| SolanaAdapter.request_rule(&solana_request(method, params), &options(), 0);
+
+        assert_eq!(rule("getBlock", json!([100])), Some(CacheRule::Policy(CachePolicy::ForeverIfNotNull)));
+        assert_eq!(rule("getBlock", json!([100, { "commitment": "confirmed" }])), Some(CacheRule::Ttl(2000000)));
+        assert_eq!(rule("getBalance", json!(["addr"])), Some(CacheRule::Ttl(2000000)));
+        assert_eq!(rule("getBalance", json!(["addr", { "commitment": "processed" }])), Some(CacheRule::Policy(CachePolicy::Never)));
+    }
+
+    #[test]
+    fn solana_health_probe_marks_unhealthy_nodes() {
+        let mut health = Health::default();
+        SolanaAdapter.inspect_probe("getHealth", &Some(json!("ok")), &mut health);
+        assert!(health.healthy);
+
+        SolanaAdapter.inspect_probe("getSlot", &None, &mut health);
+        assert!(health.healthy);
+
+        SolanaAdapter.inspect_probe("getHealth", &None, &mut health);
+        assert!(!health.healthy);
+        assert!(health.error.is_some());
+    }
+}
@@ -0,0 +1,246 @@
+use std::{collections::VecDeque, time::{Duration, SystemTime}};
+use serde::{Serialize, Deserialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CircuitBreakerOptions {
+    // consecutive failures that open the circuit
+    #[serde(default = "default_failure_threshold")]
+    pub failure_threshold: u64,
+    // share of failed requests in the window that opens the circuit
+    #[serde(default = "default_error_rate")]
+    pub error_rate: f64,
+    // number of recent requests the error rate is computed over
+    #[serde(default = "default_window")]
+    pub window: usize,
+    // milliseconds an open circuit waits before letting a trial request through
+    #[serde(default = "default_cool_down")]
+    pub cool_down: u64
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CircuitState {
+    #[default]
+    Closed,
+    Open,
+    HalfOpen
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CircuitBreaker {
+    pub state: CircuitState,
+    pub consecutive_failures: u64,
+    // outcomes of the latest requests, true for a success
+    pub recent: VecDeque<bool>,
+    pub opened_at: Option<SystemTime>,
+    // a trial in flight when the state was saved never comes back
+    #[serde(skip)]
+    pub trial_in_flight: bool
+}
+
+fn default_failure_threshold() -> u64 {
+    5
+}
+
+fn default_error_rate() -> f64 {
+    0.5
+}
+
+fn default_window() -> usize {
+    20
+}
+
+fn default_cool_down() -> u64 {
+    30000
+}
+
+impl Default for CircuitBreakerOptions {
+    fn default() -> CircuitBreakerOptions {
+        CircuitBreakerOptions {
+            failure_threshold: default_failure_threshold(),
+            error_rate: default_error_rate(),
+            window: default_window(),
+            cool_down: default_cool_down()
+        }
+    }
+}
+
+impl CircuitBreaker {
+    // whether a request can be sent, moves an open circuit to half open once the cool down passed
+    pub fn is_available(&mut self, options: &CircuitBreakerOptions) -> bool {
+        match self.state {
+            CircuitState::Closed => true,
+            CircuitState::HalfOpen => !self.trial_in_flight,
+            CircuitState::Open => {
+                let cooled_down = self.opened_at
+                    .and_then(|opened_at| SystemTime::now().duration_since(opened_at).ok())
+                    .map(|elapsed| elapsed >= Duration::from_millis(options.cool_down))
+                    .unwrap_or(true);
+
+                if cooled_down {
+                    self.state = CircuitState::HalfOpen;
+                    self.trial_in_flight = false;
+                }
+
+                cooled_down
+            }
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state == CircuitState::Closed
+    }
+
+    // called when a request is sent, a half open circuit lets a single trial through
+    pub fn on_request(&mut self) {
+        if self.state == CircuitState::HalfOpen {
+            self.trial_in_flight = true;
+        }
+    }
+
+    // the trial request was dropped before its outcome was known, the next request gets to try
+    pub fn release_trial(&mut self) {
+        if self.state == CircuitState::HalfOpen {
+            self.trial_in_flight = false;
+        }
+    }
+
+    pub fn record_success(&mut self, options: &CircuitBreakerOptions) {
+        if self.state == CircuitState::HalfOpen {
+            log::info!("Trial request succeeded, closing circuit");
+            *self = CircuitBreaker::default();
+        }
+
+        self.consecutive_failures = 0;
+        self.push(true, options);
+    }
+
+    pub fn record_failure(&mut self, options: &CircuitBreakerOptions) {
+        self.consecutive_failures += 1;
+        self.push(false, options);
+
+        let failures = self.recent.iter().filter(|success| !**success).count();
+        let error_rate = failures as f64 / self.recent.len() as f64;
+
+        let trips = self.state == CircuitState::HalfOpen
+            || self.consecutive_failures >= options.failure_threshold
+            || (self.recent.len() >= options.window && error_rate >= options.error_rate);
+
+        if trips {
+            self.open();
+        }
+    }
+
+    fn open(&mut self) {
+        log::warn!("Opening circuit after {} consecutive failures", self.consecutive_failures);
+
+        self.state = CircuitState::Open;
+        self.opened_at = Some(SystemTime::now());
+        self.trial_in_flight = false;
+    }
+
+    fn push(&mut self, success: bool, options: &CircuitBreakerOptions) {
+        self.recent.push_back(success);
+
+        while self.recent.len() > options.window {
+            self.recent.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(cool_down: u64) -> CircuitBreakerOptions {
+        CircuitBreakerOptions { failure_threshold: 3, error_rate: 0.5, window: 4, cool_down }
+    }
+
+    // an open circuit whose cool down is over
+    fn half_open() -> (CircuitBreaker, CircuitBreakerOptions) {
+        let options = options(0);
+        let mut circuit = CircuitBreaker::default();
+
+        circuit.open();
+        assert!(circuit.is_available(&options));
+        assert_eq!(circuit.state, CircuitState::HalfOpen);
+
+        (circuit, options)
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let options = options(60000);
+        let mut circuit = CircuitBreaker::default();
+
+        circuit.record_failure(&options);
+        circuit.record_failure(&options);
+        assert!(circuit.is_closed());
+
+        circuit.record_failure(&options);
+        assert_eq!(circuit.state, CircuitState::Open);
+        assert!(!circuit.is_available(&options));
+    }
+
+    #[test]
+    fn opens_on_error_rate_once_the_window_is_full() {
+        let options = options(60000);
+        let mut circuit = CircuitBreaker::default();
+
+        circuit.record_failure(&options);
+        circuit.record_success(&options);
+        circuit.record_failure(&options);
+        assert!(circuit.is_closed());
+
+        circuit.record_success(&options);
+        // 2 of the last 4 failed, with never more than one in a row
+        assert_eq!(circuit.consecutive_failures, 0);
+        assert!(circuit.is_closed());
+
+        circuit.record_failure(&options);
+        assert_eq!(circuit.state, CircuitState::Open);
+    }
+
+    #[test]
+    fn half_open_lets_a_single_trial_through() {
+        let (mut circuit, options) = half_open();
+
+        circuit.on_request();
+
+        assert!(!circuit.is_available(&options));
+    }
+
+    #[test]
+    fn successful_trial_closes() {
+        let (mut circuit, options) = half_open();
+
+        circuit.on_request();
+        circuit.record_success(&options);
+
+        assert!(circuit.is_closed());
+        assert!(!circuit.trial_in_flight);
+        assert_eq!(circuit.recent.len(), 1);
+    }
+
+    #[test]
+    fn failed_trial_opens_again() {
+        let (mut circuit, _) = half_open();
+        let options = options(60000);
+
+        circuit.on_request();
+        circuit.record_failure(&options);
+
+        assert_eq!(circuit.state, CircuitState::Open);
+        assert!(!circuit.is_available(&options));
+    }
+
+    #[test]
+    fn released_trial_lets_the_next_one_through() {
+        let (mut circuit, options) = half_open();
+
+        circuit.on_request();
+        circuit.release_trial();
+
+        assert_eq!(circuit.state, CircuitState::HalfOpen);
+        assert!(circuit.is_available(&options));
+    }
+}
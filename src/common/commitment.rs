@@ -0,0 +1,74 @@
+use crate::common::types::RpcRequest;
+
+// how settled the state a Solana read is served from is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Commitment {
+    Processed,
+    Confirmed,
+    Finalized
+}
+
+impl Commitment {
+    // nodes serve finalized state when the request doesn't ask for another commitment
+    pub fn from_request(request: &RpcRequest) -> Commitment {
+        // the config object comes last in positional params
+        request.params.as_array().into_iter().flatten()
+            .find_map(|param| param.get("commitment").and_then(|commitment| commitment.as_str()).and_then(Commitment::parse))
+            .unwrap_or(Commitment::Finalized)
+    }
+
+    // the deprecated names are still accepted by nodes
+    pub fn parse(commitment: &str) -> Option<Commitment> {
+        match commitment {
+            "processed" | "recent" => Some(Commitment::Processed),
+            "confirmed" | "single" | "singleGossip" => Some(Commitment::Confirmed),
+            "finalized" | "max" | "root" => Some(Commitment::Finalized),
+            _ => None
+        }
+    }
+}
+
+// lookups of a slot or a signature, their result never changes once finalized
+pub fn is_immutable_lookup(method: &str) -> bool {
+    matches!(
+        method,
+        "getBlock"
+            | "getBlockTime"
+            | "getTransaction"
+            | "getConfirmedBlock"
+            | "getConfirmedTransaction"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, json};
+
+    use super::*;
+    use crate::common::types::NumberString;
+
+    fn request(params: Value) -> RpcRequest {
+        RpcRequest { jsonrpc: "2.0".into(), method: "getBalance".into(), params, id: NumberString::Number(1.into()) }
+    }
+
+    #[test]
+    fn parses_current_and_deprecated_names() {
+        assert_eq!(Commitment::parse("processed"), Some(Commitment::Processed));
+        assert_eq!(Commitment::parse("recent"), Some(Commitment::Processed));
+        assert_eq!(Commitment::parse("confirmed"), Some(Commitment::Confirmed));
+        assert_eq!(Commitment::parse("singleGossip"), Some(Commitment::Confirmed));
+        assert_eq!(Commitment::parse("finalized"), Some(Commitment::Finalized));
+        assert_eq!(Commitment::parse("root"), Some(Commitment::Finalized));
+        assert_eq!(Commitment::parse("Finalized"), None);
+    }
+
+    #[test]
+    fn reads_the_commitment_of_the_config_object() {
+        assert_eq!(Commitment::from_request(&request(json!(["addr", { "commitment": "processed" }]))), Commitment::Processed);
+        assert_eq!(Commitment::from_request(&request(json!([{ "commitment": "confirmed", "encoding": "json" }]))), Commitment::Confirmed);
+        // nodes default to finalized
+        assert_eq!(Commitment::from_request(&request(json!(["addr"]))), Commitment::Finalized);
+        assert_eq!(Commitment::from_request(&request(json!(["addr", { "commitment": "unknown" }]))), Commitment::Finalized);
+        assert_eq!(Commitment::from_request(&request(Value::Null)), Commitment::Finalized);
+    }
+}
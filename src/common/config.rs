@@ -0,0 +1,256 @@
+use std::{fmt, path::Path};
+use serde::{Serialize, Deserialize};
+use actix_web::http::StatusCode;
+
+use crate::{common::{types::{Balancing, Blockchain, CacheOptions, FailoverOptions, Quorum, default_history_size}, circuit_breaker::CircuitBreakerOptions, health::HealthOptions, helper::default_forward, http::HttpOptions, state::StateOptions}, execution::coalesce::default_coalesce};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub server: ServerConfig,
+    pub chains: Vec<ChainConfig>
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerConfig {
+    // http status sent with JSON-RPC error responses, batches are always sent with 200
+    #[serde(default = "default_error_status")]
+    pub error_status: u16,
+    // where upstream state is kept between restarts
+    #[serde(default)]
+    pub state: StateOptions
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChainConfig {
+    pub chain_id: String,
+    pub chain_type: Blockchain,
+    pub rpcs: Vec<UpstreamConfig>,
+    pub max_connections: u64,
+    pub max_responses: u64,
+    pub max_retries: u64,
+    // requests remembered per upstream
+    #[serde(default = "default_history_size")]
+    pub history_size: usize,
+    // identical requests in flight at the same time share one upstream call, writes never do
+    #[serde(default = "default_coalesce")]
+    pub coalesce: bool,
+    // uncached responses are passed through without being parsed
+    #[serde(default = "default_forward")]
+    pub forward: bool,
+    #[serde(default)]
+    pub cache: CacheOptions,
+    // how many upstreams have to agree on /ultra routes
+    #[serde(default)]
+    pub quorum: Quorum,
+    #[serde(default)]
+    pub balancing: Balancing,
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerOptions,
+    #[serde(default)]
+    pub failover: FailoverOptions,
+    #[serde(default)]
+    pub health: HealthOptions,
+    // timeouts and connection pooling of the upstream clients
+    #[serde(default)]
+    pub http: HttpOptions,
+    // route the chain is mounted on, defaults to `/<chain_id>`
+    pub route: Option<String>
+}
+
+// an upstream is either its url or a table with the url, its weight and its websocket
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum UpstreamConfig {
+    Url(String),
+    Table {
+        url: String,
+        #[serde(default = "default_weight")]
+        weight: u64,
+        // used for subscriptions, defaults to `url` with a ws scheme
+        #[serde(default)]
+        ws: Option<String>
+    }
+}
+
+#[derive(Debug)]
+pub struct ConfigError {
+    pub error: String
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid config: {}", self.error)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Config, ConfigError> {
+        let path = path.as_ref();
+
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| ConfigError { error: format!("could not read {}: {}", path.display(), err) })?;
+
+        Config::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Config, ConfigError> {
+        let config = toml::from_str::<Config>(text)
+            .map_err(|err| ConfigError { error: err.to_string() })?;
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    // also run on configs built from the command line
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut routes = vec![];
+        let mut chain_ids = vec![];
+
+        if StatusCode::from_u16(self.server.error_status).is_err() {
+            return Err(ConfigError { error: format!("{} is not a valid http status", self.server.error_status) });
+        }
+
+        for chain in self.chains.iter() {
+            if chain.rpcs.is_empty() {
+                return Err(ConfigError { error: format!("chain {} has no rpcs", chain.chain_id) });
+            }
+
+            // it caps the attempts of a request, 0 would fail every request without sending it
+            if chain.max_retries == 0 {
+                return Err(ConfigError { error: format!("chain {} has max_retries of 0, at least 1 attempt is needed", chain.chain_id) });
+            }
+
+            if let Quorum::Count(count) = chain.quorum {
+                if count == 0 || count > chain.rpcs.len() {
+                    return Err(ConfigError { error: format!("chain {} has a quorum of {} with {} rpcs", chain.chain_id, count, chain.rpcs.len()) });
+                }
+            }
+
+            // clients keep their state under the chain id
+            if chain_ids.contains(&&chain.chain_id) {
+                return Err(ConfigError { error: format!("chain {} is configured more than once", chain.chain_id) });
+            }
+
+            chain_ids.push(&chain.chain_id);
+
+            let route = chain.route();
+
+            if routes.contains(&route) {
+                return Err(ConfigError { error: format!("route {} is used by more than one chain", route) });
+            }
+
+            routes.push(route);
+        }
+
+        Ok(())
+    }
+}
+
+fn default_weight() -> u64 {
+    1
+}
+
+fn default_error_status() -> u16 {
+    200
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig { error_status: default_error_status(), state: StateOptions::default() }
+    }
+}
+
+impl ServerConfig {
+    pub fn error_status(&self) -> StatusCode {
+        StatusCode::from_u16(self.error_status).unwrap_or(StatusCode::OK)
+    }
+}
+
+impl UpstreamConfig {
+    pub fn url(&self) -> &String {
+        match self {
+            UpstreamConfig::Url(url) => url,
+            UpstreamConfig::Table { url, .. } => url
+        }
+    }
+
+    pub fn weight(&self) -> u64 {
+        match self {
+            UpstreamConfig::Url(_) => default_weight(),
+            UpstreamConfig::Table { weight, .. } => *weight
+        }
+    }
+
+    pub fn ws(&self) -> Option<&String> {
+        match self {
+            UpstreamConfig::Url(_) => None,
+            UpstreamConfig::Table { ws, .. } => ws.as_ref()
+        }
+    }
+}
+
+impl ChainConfig {
+    pub fn route(&self) -> String {
+        let route = self.route.clone().unwrap_or_else(|| self.chain_id.clone());
+
+        format!("/{}", route.trim_matches('/'))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHAIN: &str = "[[chains]]\nchain_id = \"5\"\nchain_type = \"Evm\"\nmax_connections = 10\nmax_responses = 10\n";
+
+    fn chain(rest: &str) -> String {
+        format!("{}{}\n", CHAIN, rest)
+    }
+
+    fn error(text: &str) -> String {
+        Config::parse(text).unwrap_err().error
+    }
+
+    #[test]
+    fn parses_defaults_and_upstream_tables() {
+        let config = Config::parse(&chain("max_retries = 3\nrpcs = [\"http://a\", { url = \"http://b\", weight = 3, ws = \"wss://b\" }]")).unwrap();
+        let chain = &config.chains[0];
+
+        assert_eq!(config.server.error_status, 200);
+        assert_eq!(chain.route(), "/5");
+        assert!(chain.coalesce);
+        assert_eq!(chain.rpcs[0].url(), "http://a");
+        assert_eq!(chain.rpcs[0].weight(), 1);
+        assert_eq!(chain.rpcs[0].ws(), None);
+        assert_eq!(chain.rpcs[1].weight(), 3);
+        assert_eq!(chain.rpcs[1].ws().map(String::as_str), Some("wss://b"));
+    }
+
+    #[test]
+    fn routes_are_trimmed() {
+        let config = Config::parse(&chain("max_retries = 3\nrpcs = [\"http://a\"]\nroute = \"/eth/\"")).unwrap();
+
+        assert_eq!(config.chains[0].route(), "/eth");
+    }
+
+    #[test]
+    fn rejects_invalid_configs() {
+        assert!(error("chains = 1").contains("invalid type"));
+        assert!(error(&format!("[server]\nerror_status = 1000\n{}", chain("max_retries = 3\nrpcs = [\"http://a\"]"))).contains("not a valid http status"));
+        assert!(error(&chain("max_retries = 3\nrpcs = []")).contains("has no rpcs"));
+        assert!(error(&chain("max_retries = 0\nrpcs = [\"http://a\"]")).contains("max_retries of 0"));
+        assert!(error(&chain("max_retries = 3\nrpcs = [\"http://a\"]\nquorum = 2")).contains("quorum of 2"));
+    }
+
+    #[test]
+    fn rejects_chains_sharing_an_id_or_a_route() {
+        let first = chain("max_retries = 3\nrpcs = [\"http://a\"]");
+
+        assert!(error(&format!("{}{}", first, first.replace("[[chains]]", "[[chains]]\nroute = \"other\""))).contains("configured more than once"));
+        assert!(error(&format!("{}{}", first, first.replace("chain_id = \"5\"", "chain_id = \"1\"\nroute = \"5\""))).contains("route /5"));
+    }
+}
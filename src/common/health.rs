@@ -0,0 +1,209 @@
+use std::time::SystemTime;
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+
+use crate::common::{chain::ChainAdapter, helper::request_and_record, types::{NumberString, RpcRequest}};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthOptions {
+    // milliseconds between two rounds of probes, 0 turns the checker off
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    // methods sent to every upstream on each round, defaults to the ones of the chain type
+    #[serde(default)]
+    pub probes: Option<Vec<String>>,
+    // blocks an upstream can be behind the highest one seen before it counts as lagging, 0 turns it off
+    #[serde(default = "default_max_block_lag")]
+    pub max_block_lag: u64,
+    #[serde(default)]
+    pub lag_policy: LagPolicy
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LagPolicy {
+    // lagging upstreams are only used when no other one is left, like unhealthy ones
+    #[default]
+    Exclude,
+    // lagging upstreams still get traffic, but are ranked behind the ones that are in sync
+    Downrank
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Health {
+    pub healthy: bool,
+    pub syncing: bool,
+    pub latest_block: Option<u64>,
+    // blocks behind the highest block seen on any upstream
+    #[serde(default)]
+    pub lag: Option<u64>,
+    #[serde(default)]
+    pub lagging: bool,
+    pub last_checked: Option<SystemTime>,
+    pub error: Option<String>
+}
+
+// outcome of probing one upstream
+#[derive(Debug, Clone)]
+pub struct Probe {
+    pub health: Health,
+    // average time the probes took, None when every probe failed
+    pub time_taken: Option<u128>
+}
+
+fn default_interval() -> u64 {
+    30000
+}
+
+fn default_max_block_lag() -> u64 {
+    10
+}
+
+impl Default for HealthOptions {
+    fn default() -> HealthOptions {
+        HealthOptions { interval: default_interval(), probes: None, max_block_lag: default_max_block_lag(), lag_policy: LagPolicy::default() }
+    }
+}
+
+// upstreams are trusted until a probe says otherwise
+impl Default for Health {
+    fn default() -> Health {
+        Health { healthy: true, syncing: false, latest_block: None, lag: None, lagging: false, last_checked: None, error: None }
+    }
+}
+
+impl HealthOptions {
+    pub fn probes(&self, adapter: &dyn ChainAdapter) -> Vec<String> {
+        self.probes.clone().unwrap_or_else(|| adapter.default_probes())
+    }
+}
+
+impl Health {
+    // updates the lag against `highest`, the highest block seen on any upstream
+    pub fn update_lag(&mut self, highest: u64, options: &HealthOptions) {
+        self.lag = self.latest_block.map(|latest_block| highest.saturating_sub(latest_block));
+        self.lagging = options.max_block_lag > 0 && self.lag.map(|lag| lag > options.max_block_lag).unwrap_or(false);
+    }
+
+    // whether the upstream should only be used when no other one is left
+    pub fn is_excluded(&self, options: &HealthOptions) -> bool {
+        !self.healthy || (self.lagging && options.lag_policy == LagPolicy::Exclude)
+    }
+}
+
+pub async fn probe_upstream(client: reqwest::Client, url: &String, chain_id: &str, adapter: &dyn ChainAdapter, probes: &[String]) -> Probe {
+    let mut health = Health { last_checked: Some(SystemTime::now()), ..Default::default() };
+    let mut times = vec![];
+
+    // the identity goes first even when it isn't listed, nothing else an upstream says counts without it
+    let identity = adapter.identity_method().to_string();
+    let probes = std::iter::once(&identity).chain(probes.iter().filter(|method| **method != identity));
+
+    for method in probes {
+        let request = RpcRequest { jsonrpc: "2.0".into(), method: method.clone(), params: Value::Array(vec![]), id: NumberString::Number(1.into()) };
+
+        let res = match request_and_record(&client, url, &request).await {
+            Ok(res) => res,
+            Err(err) => {
+                health.healthy = false;
+                health.error = Some(format!("{}: {}", method, err.error));
+                continue;
+            }
+        };
+
+        times.push(res.time_taken);
+
+        if res.error.is_some() {
+            health.healthy = false;
+            health.error = Some(format!("{}: {:?}", method, res.error));
+            continue;
+        }
+
+        if method == adapter.identity_method() {
+            if let Err(error) = adapter.verify_identity(chain_id, &res.result) {
+                health.healthy = false;
+                health.error = Some(error);
+            }
+        } else if method == adapter.head_method() {
+            health.latest_block = adapter.parse_head(&res.result);
+        } else {
+            adapter.inspect_probe(method, &res.result, &mut health);
+        }
+    }
+
+    let time_taken = if times.is_empty() {
+        None
+    } else {
+        Some(times.iter().sum::<u128>() / times.len() as u128)
+    };
+
+    Probe { health, time_taken }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::common::types::Blockchain;
+
+    fn options(max_block_lag: u64, lag_policy: LagPolicy) -> HealthOptions {
+        HealthOptions { max_block_lag, lag_policy, ..Default::default() }
+    }
+
+    fn at(latest_block: Option<u64>) -> Health {
+        Health { latest_block, ..Default::default() }
+    }
+
+    #[test]
+    fn lag_is_measured_against_the_highest_block() {
+        let options = options(10, LagPolicy::Exclude);
+
+        let mut close = at(Some(95));
+        close.update_lag(100, &options);
+        assert_eq!((close.lag, close.lagging), (Some(5), false));
+
+        let mut behind = at(Some(89));
+        behind.update_lag(100, &options);
+        assert_eq!((behind.lag, behind.lagging), (Some(11), true));
+
+        // no head known, nothing to compare
+        let mut unknown = at(None);
+        unknown.update_lag(100, &options);
+        assert_eq!((unknown.lag, unknown.lagging), (None, false));
+    }
+
+    #[test]
+    fn lag_of_0_turns_the_check_off() {
+        let mut behind = at(Some(1));
+        behind.update_lag(100, &options(0, LagPolicy::Exclude));
+
+        assert!(!behind.lagging);
+    }
+
+    #[test]
+    fn lag_policy_decides_exclusion() {
+        let mut behind = at(Some(1));
+        behind.update_lag(100, &options(10, LagPolicy::Exclude));
+
+        assert!(behind.is_excluded(&options(10, LagPolicy::Exclude)));
+        assert!(!behind.is_excluded(&options(10, LagPolicy::Downrank)));
+
+        let unhealthy = Health { healthy: false, ..Default::default() };
+        assert!(unhealthy.is_excluded(&options(10, LagPolicy::Downrank)));
+    }
+
+    #[test]
+    fn syncing_and_unhealthy_probes() {
+        let mut health = Health::default();
+        Blockchain::Evm.adapter().inspect_probe("eth_syncing", &Some(json!(false)), &mut health);
+        assert!(health.healthy);
+
+        Blockchain::Evm.adapter().inspect_probe("eth_syncing", &Some(json!({ "currentBlock": "0x1" })), &mut health);
+        assert!(!health.healthy && health.syncing);
+
+        let mut health = Health::default();
+        Blockchain::Solana.adapter().inspect_probe("getHealth", &Some(json!("behind")), &mut health);
+        assert!(!health.healthy);
+    }
+}
@@ -1,27 +1,38 @@
-use crate::common::types::{RpcResponse, RpcRequest, Response, RpcError};
+use crate::common::types::{RpcResponse, RpcRequest, Response, RpcError, ErrorCode, NumberString};
 use actix_web::web::Bytes;
 use reqwest::*;
+use serde::{Serialize, Deserialize, Deserializer};
+use serde_json::{Value, value::RawValue};
 use std::result::Result;
 use std::time::*;
 
-use super::types::NumberString;
-
-#[macro_export]
-macro_rules! extract_enum_value {
-  ($value:expr, $pattern:pat => $extracted_value:expr) => {
-    match $value {
-      $pattern => $extracted_value,
-      _ => panic!("Pattern doesn't match!"),
+pub fn upstream_error(code: ErrorCode, error: String, body: &RpcRequest, time_taken: u128) -> RpcError {
+    RpcError {
+        code,
+        jsonrpc: body.jsonrpc.clone(),
+        id: body.id.clone(),
+        error,
+        method: body.method.clone(),
+        params: body.params.clone(),
+        time_taken,
+        data: None
     }
-  };
 }
 
-pub async fn request_and_record(url: &String, body: &RpcRequest) -> Result<Response, RpcError> {
+// a timed out attempt gets its own code, failover moves on from it like from any other failed attempt
+fn transport_error(err: Error, code: ErrorCode, body: &RpcRequest, time_taken: u128) -> RpcError {
+    let code = if err.is_timeout() { ErrorCode::UpstreamTimeout } else { code };
+
+    upstream_error(code, err.to_string(), body, time_taken)
+}
+
+// `client` is the pooled client of the upstream at `url`
+pub async fn request_and_record(client: &Client, url: &String, body: &RpcRequest) -> Result<Response, RpcError> {
     // println!("STARTED {} {:?}", url, body);
-    let client = Client::builder().build().unwrap();
     let start = Instant::now();
     
-    let res= client.post(url).json(&body).send().await.unwrap();
+    let res = client.post(url).json(&body).send().await
+        .map_err(|err| transport_error(err, ErrorCode::UpstreamUnavailable, body, start.elapsed().as_millis()))?;
     // println!("completed response");
     let status = res.status();
     
@@ -29,12 +40,10 @@ pub async fn request_and_record(url: &String, body: &RpcRequest) -> Result<Respo
         let response_result = res.json::<RpcResponse>().await;
         let elapsed_time = start.elapsed();
     
-        let mut response = RpcResponse::default();
-
-        match response_result {
-            Ok(res) => response = res,
-            Err(err) => return Err(RpcError { error: err.to_string(), jsonrpc: body.jsonrpc.clone(), method: body.method.clone(), params: body.params.clone(), id: body.id.clone(), time_taken: elapsed_time.as_millis() })
-        }
+        let response = match response_result {
+            Ok(res) => res,
+            Err(err) => return Err(transport_error(err, ErrorCode::InvalidUpstreamResponse, body, elapsed_time.as_millis()))
+        };
     
         let response: Response = Response {
             method: body.method.clone(),
@@ -42,46 +51,170 @@ pub async fn request_and_record(url: &String, body: &RpcRequest) -> Result<Respo
             result: response.result,
             time_taken: elapsed_time.as_millis(),
             start_time: SystemTime::now(),
-            error: response.error
+            error: response.error,
+            body: None
         };
     
         Ok(response)
     } else {
         let elapsed_time = start.elapsed();
-        
-        Err(RpcError {
-            jsonrpc: body.jsonrpc.clone(),
-            id: body.id.clone(),
-            error: res.text().await.unwrap().into(),
-            method: body.method.clone(),
-            params: body.params.clone(),
-            time_taken: elapsed_time.as_millis()
-        })
+        let error = res.text().await.unwrap_or_else(|err| err.to_string());
+
+        Err(upstream_error(ErrorCode::UpstreamUnavailable, format!("{}: {}", status, error), body, elapsed_time.as_millis()))
     }
     
 }
 
-pub async fn request_and_record_bytes(url: &String, body: &RpcRequest) -> Result<Bytes, RpcError> {
-    // println!("STARTED {} {:?}", url, body);
-    let client = Client::builder().build().unwrap();
-    
-    let res= client.post(url).json(&body).send().await.unwrap();
-    // println!("completed response");
+// whether uncached responses are forwarded as the upstream sent them
+pub fn default_forward() -> bool {
+    true
+}
+
+// the members of a JSON-RPC response, borrowed as the raw text the upstream sent
+#[derive(Serialize, Deserialize)]
+struct Envelope<'a> {
+    #[serde(borrow, default, skip_serializing_if = "Option::is_none")]
+    jsonrpc: Option<&'a RawValue>,
+    // a null result is kept as the raw `null`, so it is written back
+    #[serde(borrow, default, deserialize_with = "raw_member", skip_serializing_if = "Option::is_none")]
+    result: Option<&'a RawValue>,
+    #[serde(borrow, default, deserialize_with = "raw_member")]
+    id: Option<&'a RawValue>,
+    #[serde(borrow, default, deserialize_with = "raw_member", skip_serializing_if = "Option::is_none")]
+    error: Option<&'a RawValue>
+}
+
+fn raw_member<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<&'de RawValue>, D::Error> {
+    <&RawValue>::deserialize(deserializer).map(Some)
+}
+
+// checks that `bytes` hold a JSON-RPC response and returns its error, the result is only scanned over
+pub fn inspect_forwarded(bytes: &[u8]) -> serde_json::Result<Option<Value>> {
+    let envelope = serde_json::from_slice::<Envelope>(bytes)?;
+
+    match envelope.error.filter(|error| error.get() != "null") {
+        Some(error) => serde_json::from_str(error.get()).map(Some),
+        None => Ok(None)
+    }
+}
+
+// the result of a forwarded response, for storing it, None when it is null
+pub fn forwarded_result(bytes: &[u8]) -> serde_json::Result<Option<Value>> {
+    let envelope = serde_json::from_slice::<Envelope>(bytes)?;
+
+    match envelope.result.filter(|result| result.get() != "null") {
+        Some(result) => serde_json::from_str(result.get()).map(Some),
+        None => Ok(None)
+    }
+}
+
+// `body` as the answer to the request with `id`, only copied around its raw result when the upstream used another id
+pub fn forwarded_body(body: &Bytes, id: &NumberString) -> Bytes {
+    let id = match serde_json::to_string(id).ok().and_then(|id| RawValue::from_string(id).ok()) {
+        Some(id) => id,
+        None => return body.clone()
+    };
+
+    let mut envelope = match serde_json::from_slice::<Envelope>(body) {
+        Ok(envelope) => envelope,
+        Err(_) => return body.clone()
+    };
+
+    if envelope.id.map(RawValue::get) == Some(id.get()) {
+        return body.clone();
+    }
+
+    envelope.id = Some(&id);
+
+    serde_json::to_vec(&envelope).map(Bytes::from).unwrap_or_else(|_| body.clone())
+}
+
+// like `request_and_record`, but the body is kept as it came and only its envelope is checked
+pub async fn request_and_forward(client: &Client, url: &String, body: &RpcRequest) -> Result<Response, RpcError> {
+    let start = Instant::now();
+
+    let res = client.post(url).json(&body).send().await
+        .map_err(|err| transport_error(err, ErrorCode::UpstreamUnavailable, body, start.elapsed().as_millis()))?;
     let status = res.status();
 
     if status == StatusCode::OK || status == StatusCode::ACCEPTED || status == StatusCode::CREATED {
-        let bytes = res.bytes().await.unwrap();
+        let bytes = res.bytes().await
+            .map_err(|err| transport_error(err, ErrorCode::UpstreamUnavailable, body, start.elapsed().as_millis()))?;
+        let elapsed_time = start.elapsed();
 
-        Ok(bytes)
-    } else {
-        Err(RpcError {
-            jsonrpc: body.jsonrpc.clone(),
-            id: body.id.clone(),
-            error: res.text().await.unwrap().into(),
+        let error = inspect_forwarded(&bytes)
+            .map_err(|err| upstream_error(ErrorCode::InvalidUpstreamResponse, err.to_string(), body, elapsed_time.as_millis()))?;
+
+        Ok(Response {
             method: body.method.clone(),
             params: body.params.clone(),
-            time_taken: 0
+            result: None,
+            time_taken: elapsed_time.as_millis(),
+            start_time: SystemTime::now(),
+            error,
+            body: Some(bytes)
         })
+    } else {
+        let elapsed_time = start.elapsed();
+        let error = res.text().await.unwrap_or_else(|err| err.to_string());
+
+        Err(upstream_error(ErrorCode::UpstreamUnavailable, format!("{}: {}", status, error), body, elapsed_time.as_millis()))
     }
-    
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn same_id_keeps_the_body() {
+        let body = Bytes::from_static(br#"{"jsonrpc": "2.0", "id": 1, "result": {"b": 1, "a": [1, 2]}}"#);
+        let forwarded = forwarded_body(&body, &NumberString::Number(1.into()));
+
+        assert_eq!(forwarded, body);
+        // the same buffer, not a copy
+        assert_eq!(forwarded.as_ptr(), body.as_ptr());
+    }
+
+    #[test]
+    fn other_id_is_rewritten_around_the_raw_result() {
+        let body = Bytes::from_static(br#"{"jsonrpc": "2.0", "id": 1, "result": {"b": 1, "a": [1, 2]}}"#);
+
+        let forwarded = forwarded_body(&body, &NumberString::Text("abc".into()));
+
+        assert_eq!(&forwarded[..], br#"{"jsonrpc":"2.0","result":{"b": 1, "a": [1, 2]},"id":"abc"}"#);
+    }
+
+    #[test]
+    fn null_result_and_error_survive_a_rewrite() {
+        let null = Bytes::from_static(br#"{"jsonrpc":"2.0","id":1,"result":null}"#);
+        let error = Bytes::from_static(br#"{"jsonrpc":"2.0","id":1,"error":{"code":-32000,"message":"boom"}}"#);
+
+        let null = serde_json::from_slice::<Value>(&forwarded_body(&null, &NumberString::Number(2.into()))).unwrap();
+        let error = serde_json::from_slice::<Value>(&forwarded_body(&error, &NumberString::Null)).unwrap();
+
+        assert_eq!(null, json!({ "jsonrpc": "2.0", "id": 2, "result": null }));
+        assert_eq!(error, json!({ "jsonrpc": "2.0", "id": null, "error": { "code": -32000, "message": "boom" } }));
+    }
+
+    #[test]
+    fn not_json_rpc_is_left_alone() {
+        let body = Bytes::from_static(b"not json");
+
+        assert_eq!(forwarded_body(&body, &NumberString::Number(2.into())), body);
+        assert!(inspect_forwarded(&body).is_err());
+    }
+
+    #[test]
+    fn inspect_and_parse_forwarded() {
+        let error = br#"{"jsonrpc":"2.0","id":1,"error":{"code":-32000,"message":"boom"}}"#;
+        let result = br#"{"jsonrpc":"2.0","id":1,"result":["0x1"]}"#;
+
+        assert_eq!(inspect_forwarded(error).unwrap(), Some(json!({ "code": -32000, "message": "boom" })));
+        assert_eq!(inspect_forwarded(result).unwrap(), None);
+        assert_eq!(forwarded_result(result).unwrap(), Some(json!(["0x1"])));
+        assert_eq!(forwarded_result(br#"{"jsonrpc":"2.0","id":1,"result":null}"#).unwrap(), None);
+    }
+}
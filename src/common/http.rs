@@ -0,0 +1,88 @@
+use std::time::Duration;
+use reqwest::Client;
+use serde::{Serialize, Deserialize};
+
+// how upstreams are talked to, every upstream gets its own client and connection pool
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HttpOptions {
+    // milliseconds to open a connection, 0 to wait as long as the system does
+    #[serde(default = "default_connect_timeout")]
+    pub connect_timeout: u64,
+    // milliseconds an attempt can take from sending the request to reading the whole response, 0 to wait forever
+    #[serde(default = "default_request_timeout")]
+    pub request_timeout: u64,
+    // idle connections kept open to an upstream
+    #[serde(default = "default_max_idle")]
+    pub max_idle: usize,
+    // milliseconds an idle connection is kept open
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout: u64,
+    // milliseconds between TCP keep-alive probes, 0 turns them off
+    #[serde(default = "default_keep_alive")]
+    pub keep_alive: u64,
+    // speak HTTP/2 right away to plain http upstreams known to support it, https upstreams
+    // negotiate it during the TLS handshake whatever this says
+    #[serde(default)]
+    pub http2: bool
+}
+
+fn default_connect_timeout() -> u64 {
+    5000
+}
+
+pub fn default_request_timeout() -> u64 {
+    30000
+}
+
+fn default_max_idle() -> usize {
+    32
+}
+
+fn default_idle_timeout() -> u64 {
+    90000
+}
+
+fn default_keep_alive() -> u64 {
+    60000
+}
+
+impl Default for HttpOptions {
+    fn default() -> HttpOptions {
+        HttpOptions {
+            connect_timeout: default_connect_timeout(),
+            request_timeout: default_request_timeout(),
+            max_idle: default_max_idle(),
+            idle_timeout: default_idle_timeout(),
+            keep_alive: default_keep_alive(),
+            http2: false
+        }
+    }
+}
+
+impl HttpOptions {
+    // the client of the upstream at `url`
+    pub fn client(&self, url: &str) -> reqwest::Result<Client> {
+        let mut builder = Client::builder()
+            .pool_max_idle_per_host(self.max_idle)
+            .pool_idle_timeout(Duration::from_millis(self.idle_timeout));
+
+        if self.connect_timeout > 0 {
+            builder = builder.connect_timeout(Duration::from_millis(self.connect_timeout));
+        }
+
+        if self.request_timeout > 0 {
+            builder = builder.timeout(Duration::from_millis(self.request_timeout));
+        }
+
+        if self.keep_alive > 0 {
+            builder = builder.tcp_keepalive(Duration::from_millis(self.keep_alive));
+        }
+
+        // prior knowledge skips ALPN, over TLS it would break upstreams that only speak HTTP/1.1
+        if self.http2 && url.starts_with("http://") {
+            builder = builder.http2_prior_knowledge();
+        }
+
+        builder.build()
+    }
+}
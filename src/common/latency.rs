@@ -0,0 +1,113 @@
+use std::collections::VecDeque;
+use serde::{Serialize, Deserialize};
+
+// weight of the newest sample in the moving average
+const ALPHA: f64 = 0.3;
+
+// latencies of the last `window` requests, errors are tracked by the circuit breaker
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LatencyTracker {
+    pub ewma: Option<f64>,
+    pub window: usize,
+    pub samples: VecDeque<u128>
+}
+
+fn default_window() -> usize {
+    100
+}
+
+impl Default for LatencyTracker {
+    fn default() -> LatencyTracker {
+        LatencyTracker { ewma: None, window: default_window(), samples: VecDeque::new() }
+    }
+}
+
+impl LatencyTracker {
+    pub fn record(&mut self, time_taken: u128) {
+        self.ewma = Some(match self.ewma {
+            Some(ewma) => ALPHA * time_taken as f64 + (1.0 - ALPHA) * ewma,
+            None => time_taken as f64
+        });
+
+        self.samples.push_back(time_taken);
+
+        while self.samples.len() > self.window.max(1) {
+            self.samples.pop_front();
+        }
+    }
+
+    // moving average in milliseconds, 0 until the first sample
+    pub fn average(&self) -> u128 {
+        self.ewma.map(|ewma| ewma.round() as u128).unwrap_or(0)
+    }
+
+    // latency below which `percentile` percent of the window falls, nearest rank
+    pub fn percentile(&self, percentile: f64) -> Option<u128> {
+        if self.samples.is_empty() {
+            return None;
+        }
+
+        let mut sorted = self.samples.iter().copied().collect::<Vec<_>>();
+        sorted.sort_unstable();
+
+        let rank = ((percentile / 100.0) * sorted.len() as f64).ceil() as usize;
+
+        Some(sorted[rank.clamp(1, sorted.len()) - 1])
+    }
+
+    // halfway between the average and p95, so an upstream with a slow tail doesn't pass for as fast as its average
+    pub fn tail_weighted(&self) -> u128 {
+        match self.p95() {
+            Some(p95) => (self.average() + p95) / 2,
+            None => self.average()
+        }
+    }
+
+    pub fn p50(&self) -> Option<u128> {
+        self.percentile(50.0)
+    }
+
+    pub fn p95(&self) -> Option<u128> {
+        self.percentile(95.0)
+    }
+
+    pub fn p99(&self) -> Option<u128> {
+        self.percentile(99.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracker(samples: &[u128]) -> LatencyTracker {
+        let mut tracker = LatencyTracker::default();
+
+        for sample in samples {
+            tracker.record(*sample);
+        }
+
+        tracker
+    }
+
+    #[test]
+    fn percentiles_use_nearest_rank() {
+        let tracker = tracker(&(1..=100).collect::<Vec<_>>());
+
+        assert_eq!(tracker.p50(), Some(50));
+        assert_eq!(tracker.p95(), Some(95));
+        assert_eq!(tracker.p99(), Some(99));
+        assert_eq!(LatencyTracker::default().p50(), None);
+    }
+
+    #[test]
+    fn slow_tail_weighs_on_steady_average() {
+        let steady = tracker(&[100; 20]);
+        // the spikes are old enough for the average to have forgotten them
+        let erratic = tracker(&[[1000, 1000].as_slice(), &[50; 18]].concat());
+
+        assert!(erratic.average() < steady.average());
+        assert_eq!(steady.tail_weighted(), 100);
+        assert!(erratic.tail_weighted() > steady.tail_weighted());
+    }
+}
@@ -1,2 +1,14 @@
 pub mod types;
-pub mod helper;
\ No newline at end of file
+pub mod cache;
+pub mod chain;
+pub mod helper;
+pub mod config;
+pub mod block_tag;
+pub mod commitment;
+pub mod circuit_breaker;
+pub mod health;
+pub mod http;
+pub mod latency;
+pub mod state;
+#[cfg(test)]
+pub mod stub;
@@ -0,0 +1,168 @@
+use std::{collections::HashMap, fmt::Debug, fs::{self, File}, io::{self, Write}, path::PathBuf, sync::{Arc, Mutex}};
+use serde::{Serialize, Deserialize};
+
+// where clients keep their upstream rankings, health and cache between restarts, keyed by chain id
+pub trait StateStore: Debug + Send + Sync {
+    fn load(&self, key: &str) -> io::Result<Option<Vec<u8>>>;
+
+    fn save(&self, key: &str, value: &[u8]) -> io::Result<()>;
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StateOptions {
+    #[serde(default)]
+    pub backend: StateBackend,
+    // directory of the file store, or of the sled database
+    #[serde(default = "default_path")]
+    pub path: PathBuf,
+    // milliseconds between two writes of a client that changed
+    #[serde(default = "default_debounce")]
+    pub debounce: u64
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StateBackend {
+    // nothing outlives the process
+    #[default]
+    Memory,
+    // one json file per chain
+    File,
+    Sled
+}
+
+fn default_path() -> PathBuf {
+    PathBuf::from("/tmp/svinge")
+}
+
+fn default_debounce() -> u64 {
+    1000
+}
+
+impl Default for StateOptions {
+    fn default() -> StateOptions {
+        StateOptions { backend: StateBackend::default(), path: default_path(), debounce: default_debounce() }
+    }
+}
+
+impl StateOptions {
+    pub fn store(&self) -> io::Result<Arc<dyn StateStore>> {
+        match self.backend {
+            StateBackend::Memory => Ok(Arc::new(MemoryStore::default())),
+            StateBackend::File => Ok(Arc::new(FileStore::open(self.path.clone())?)),
+            #[cfg(feature = "sled")]
+            StateBackend::Sled => Ok(Arc::new(SledStore::open(&self.path)?)),
+            #[cfg(not(feature = "sled"))]
+            StateBackend::Sled => Err(io::Error::other("svinge was built without the sled feature"))
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    entries: Mutex<HashMap<String, Vec<u8>>>
+}
+
+impl StateStore for MemoryStore {
+    fn load(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.entries.lock().unwrap().get(key).cloned())
+    }
+
+    fn save(&self, key: &str, value: &[u8]) -> io::Result<()> {
+        self.entries.lock().unwrap().insert(key.into(), value.to_vec());
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct FileStore {
+    dir: PathBuf
+}
+
+impl FileStore {
+    pub fn open(dir: PathBuf) -> io::Result<FileStore> {
+        fs::create_dir_all(&dir)?;
+        Ok(FileStore { dir })
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", key))
+    }
+}
+
+impl StateStore for FileStore {
+    fn load(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(self.path(key)) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err)
+        }
+    }
+
+    // writes next to the target and renames over it, so a crash never leaves a half written file
+    fn save(&self, key: &str, value: &[u8]) -> io::Result<()> {
+        let path = self.path(key);
+        let tmp = self.dir.join(format!(".{}.json.tmp", key));
+
+        let mut file = File::create(&tmp)?;
+        file.write_all(value)?;
+        file.sync_all()?;
+
+        fs::rename(tmp, path)
+    }
+}
+
+#[cfg(feature = "sled")]
+#[derive(Debug)]
+pub struct SledStore {
+    db: sled::Db
+}
+
+#[cfg(feature = "sled")]
+impl SledStore {
+    pub fn open(path: &std::path::Path) -> io::Result<SledStore> {
+        Ok(SledStore { db: sled::open(path).map_err(io::Error::other)? })
+    }
+}
+
+#[cfg(feature = "sled")]
+impl StateStore for SledStore {
+    fn load(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.db.get(key).map_err(io::Error::other)?.map(|value| value.to_vec()))
+    }
+
+    fn save(&self, key: &str, value: &[u8]) -> io::Result<()> {
+        self.db.insert(key, value).map_err(io::Error::other)?;
+        self.db.flush().map_err(io::Error::other)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_store_round_trips_across_opens() {
+        let dir = std::env::temp_dir().join(format!("svinge-state-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let store = FileStore::open(dir.clone()).unwrap();
+
+        assert_eq!(store.load("5").unwrap(), None);
+
+        store.save("5", b"{\"first\":1}").unwrap();
+        store.save("5", b"{\"second\":2}").unwrap();
+        store.save("1", b"{}").unwrap();
+
+        // a new store over the same directory, as after a restart
+        let store = FileStore::open(dir.clone()).unwrap();
+
+        assert_eq!(store.load("5").unwrap().as_deref(), Some(&b"{\"second\":2}"[..]));
+        assert_eq!(store.load("1").unwrap().as_deref(), Some(&b"{}"[..]));
+        // the temporary file was renamed over the target
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
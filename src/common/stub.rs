@@ -0,0 +1,100 @@
+use std::{sync::Arc, time::Duration};
+use serde_json::Value;
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream}};
+
+// what a stand-in upstream sends back, after waiting `delay`
+pub struct Reply {
+    pub status: u16,
+    pub body: Value,
+    pub delay: Duration
+}
+
+impl Reply {
+    pub fn ok(body: Value) -> Reply {
+        Reply { status: 200, body, delay: Duration::ZERO }
+    }
+
+    pub fn status(status: u16) -> Reply {
+        Reply { status, body: Value::Null, delay: Duration::ZERO }
+    }
+
+    pub fn after(self, delay: Duration) -> Reply {
+        Reply { delay, ..self }
+    }
+}
+
+// answers json-rpc calls over http/1.1 with what `reply` returns for each request, returns its url
+pub async fn upstream<F>(reply: F) -> String
+where
+    F: Fn(&Value) -> Reply + Send + Sync + 'static
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let reply = Arc::new(reply);
+
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            tokio::spawn(serve(socket, reply.clone()));
+        }
+    });
+
+    url
+}
+
+async fn serve<F>(mut socket: TcpStream, reply: Arc<F>)
+where
+    F: Fn(&Value) -> Reply + Send + Sync + 'static
+{
+    let mut buf = vec![];
+    let mut chunk = [0; 4096];
+
+    loop {
+        // the head of the request, then as much body as its content length says
+        let head_end = loop {
+            if let Some(end) = buf.windows(4).position(|window| window == b"\r\n\r\n") {
+                break end + 4;
+            }
+
+            match socket.read(&mut chunk).await {
+                Ok(0) | Err(_) => return,
+                Ok(read) => buf.extend_from_slice(&chunk[..read])
+            }
+        };
+
+        let head = String::from_utf8_lossy(&buf[..head_end]).to_lowercase();
+        let length = head.lines()
+            .find_map(|line| line.strip_prefix("content-length:"))
+            .and_then(|length| length.trim().parse::<usize>().ok())
+            .unwrap_or(0);
+
+        while buf.len() < head_end + length {
+            match socket.read(&mut chunk).await {
+                Ok(0) | Err(_) => return,
+                Ok(read) => buf.extend_from_slice(&chunk[..read])
+            }
+        }
+
+        let request = serde_json::from_slice::<Value>(&buf[head_end..head_end + length]).unwrap_or(Value::Null);
+        buf.drain(..head_end + length);
+
+        let reply = reply(&request);
+        tokio::time::sleep(reply.delay).await;
+
+        let body = serde_json::to_vec(&reply.body).unwrap();
+        let head = format!("HTTP/1.1 {} Stub\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n", reply.status, body.len());
+
+        if socket.write_all(&[head.into_bytes(), body].concat()).await.is_err() {
+            return;
+        }
+    }
+}
+
+// the reply of an upstream on `chain_id` for its identity call, `result` for every other method
+pub fn answer(request: &Value, chain_id: &str, result: Value) -> Reply {
+    let result = match request["method"].as_str() {
+        Some("eth_chainId") => Value::String(format!("0x{:x}", chain_id.parse::<u64>().unwrap())),
+        _ => result
+    };
+
+    Reply::ok(serde_json::json!({ "jsonrpc": "2.0", "id": request["id"], "result": result }))
+}
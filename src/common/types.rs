@@ -1,6 +1,9 @@
-use std::time::SystemTime;
+use std::{collections::{HashMap, VecDeque}, str::FromStr, time::SystemTime};
 use serde_json::{Value, Map};
-use serde::{Serialize, Deserialize};
+use actix_web::web::Bytes;
+use serde::{Serialize, Deserialize, Deserializer, Serializer, ser::SerializeStruct};
+
+use crate::common::{cache::CacheStoreOptions, chain::ChainAdapter, circuit_breaker::CircuitBreaker, health::Health, latency::LatencyTracker};
 
 #[derive(clap::ValueEnum, Debug, Clone, Serialize, Deserialize)]
 pub enum Blockchain {
@@ -12,105 +15,469 @@ pub enum Blockchain {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RPC {
     pub url: String,
+    // websocket used for subscriptions, None to derive it from `url`
+    #[serde(default)]
+    pub ws: Option<String>,
+    // moving average of `latency`, what the selectors rank upstreams by
     pub avg_response_time: u128,
+    #[serde(default)]
+    pub latency: LatencyTracker,
+    // requests in flight, never saved since none of them survive a restart
+    #[serde(skip)]
     pub connections: u64,
     pub weight: u64,
+    // running weight used by weighted round robin
+    #[serde(skip)]
+    pub current_weight: i64,
+    #[serde(default)]
+    pub circuit: CircuitBreaker,
+    #[serde(default)]
+    pub health: Health,
     pub response_counter: u64,
-    pub responses: Vec<Response>
+    // latest requests sent to the upstream, oldest first
+    #[serde(default)]
+    pub history: VecDeque<Sample>,
+    // pooled client of the upstream, built from the chain's `http` options
+    #[serde(skip)]
+    pub client: reqwest::Client
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SampleStatus {
+    Ok,
+    // the upstream answered with a JSON-RPC error
+    Error,
+    // the request did not get a valid answer at all
+    Failed
 }
 
+// what is kept of a request once it completed, without its payload
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Response {
+pub struct Sample {
     pub method: String,
-    pub params: Vec<InnerData>,
-    pub result: Option<ResponseInnerData>,
     pub time_taken: u128,
-    pub start_time: SystemTime,
-    pub error: Option<ResponseInnerData>
+    pub status: SampleStatus,
+    pub timestamp: SystemTime
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(untagged)]
-pub enum ResponseInnerData {
-    Text(String),
-    TextArray(Vec<String>),
-    NumberArray(Vec<u64>),
-    Boolean(bool),
-    Object(Map<String, Value>)
+pub fn default_history_size() -> usize {
+    100
 }
 
+impl RPC {
+    pub fn ws_url(&self) -> String {
+        match &self.ws {
+            Some(ws) => ws.clone(),
+            None if self.url.starts_with("https://") => self.url.replacen("https://", "wss://", 1),
+            None => self.url.replacen("http://", "ws://", 1)
+        }
+    }
+
+    pub fn record_latency(&mut self, time_taken: u128) {
+        self.latency.record(time_taken);
+        self.avg_response_time = self.latency.average();
+    }
+
+    // keeps at most `size` samples, dropping the oldest ones
+    pub fn record_sample(&mut self, sample: Sample, size: usize) {
+        self.history.push_back(sample);
+
+        while self.history.len() > size {
+            self.history.pop_front();
+        }
+    }
+}
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+impl Sample {
+    pub fn from_response(res: &Response) -> Sample {
+        Sample {
+            method: res.method.clone(),
+            time_taken: res.time_taken,
+            status: if res.error.is_some() { SampleStatus::Error } else { SampleStatus::Ok },
+            timestamp: res.start_time
+        }
+    }
+
+    pub fn from_error(err: &RpcError) -> Sample {
+        Sample { method: err.method.clone(), time_taken: err.time_taken, status: SampleStatus::Failed, timestamp: SystemTime::now() }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Response {
+    pub method: String,
+    pub params: Value,
+    // None when the upstream answered null
+    pub result: Option<Value>,
+    pub time_taken: u128,
+    pub start_time: SystemTime,
+    pub error: Option<Value>,
+    // what the upstream sent, on forwarded responses only, their result is left unparsed until they are stored
+    #[serde(skip)]
+    pub body: Option<Bytes>
+}
+
+#[derive(Debug, Deserialize, Clone)]
 pub struct RpcResponse {
     pub jsonrpc: String,
-    pub result: Option<ResponseInnerData>,
+    pub result: Option<Value>,
     pub id: NumberString,
-    pub error: Option<ResponseInnerData>
+    pub error: Option<Value>
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(untagged)]
-pub enum InnerData {
-    Text(String),
-    TextArray(Vec<String>),
-    NumberArray(Vec<u64>),
-    Boolean(bool),
-    Object(Map<String, Value>)
+// a response carries either `result` or `error`, a successful null result is still sent as `"result": null`
+impl Serialize for RpcResponse {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut response = serializer.serialize_struct("RpcResponse", 3)?;
+        response.serialize_field("jsonrpc", &self.jsonrpc)?;
+
+        match &self.error {
+            Some(error) => response.serialize_field("error", error)?,
+            None => response.serialize_field("result", &self.result)?
+        }
+
+        response.serialize_field("id", &self.id)?;
+        response.end()
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum NumberString {
     Text(String),
-    Number(i32)
+    Number(serde_json::Number),
+    Null
 }
 
 #[derive(Debug, Serialize, Clone, Deserialize)]
 pub struct RpcRequest {
     pub jsonrpc: String,
     pub method: String,
-    pub params: Vec<InnerData>,
+    // passed upstream as sent, positional or by name, and left out when the client left it out
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    pub params: Value,
     pub id: NumberString
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum RpcResponseBody {
+    Single(RpcResponse),
+    Batch(Vec<RpcResponse>),
+    // the upstream's bytes, written out as they are
+    #[serde(skip)]
+    Forwarded(Bytes)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    ParseError,
+    InvalidRequest,
+    // params that are neither an array nor an object, errors about the method or the content of
+    // the params come from the upstream and are passed through with their own code
+    InvalidParams,
+    InternalError,
+    UpstreamUnavailable,
+    InvalidUpstreamResponse,
+    QuorumNotReached,
+    // the upstream didn't answer in time, the request is retried on the next one
+    UpstreamTimeout
+}
+
+#[derive(Debug, Clone)]
 pub struct RpcError {
+    pub code: ErrorCode,
     pub error: String,
     pub jsonrpc: String,
     pub method: String,
-    pub params: Vec<InnerData>,
+    pub params: Value,
     pub id: NumberString,
-    pub time_taken: u128
+    pub time_taken: u128,
+    // sent as the `data` member of the JSON-RPC error object
+    pub data: Option<Value>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Quorum {
+    // number of upstreams that have to return the same response
+    Count(usize),
+    Policy(QuorumPolicy)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QuorumPolicy {
+    Majority,
+    All
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CacheOptions {
+    #[serde(deserialize_with = "deserialize_u128_from_u64")]
     pub cache_clear: u128,
-    pub exclude_methods: Vec<String>
+    pub exclude_methods: Vec<String>,
+    // per method rules, methods without one are kept for `cache_clear` microseconds
+    #[serde(default)]
+    pub methods: HashMap<String, CacheRule>,
+    // microseconds reads at latest, safe or finalized stay fresh, defaults to `cache_clear`
+    #[serde(default)]
+    pub head_ttl: Option<u64>,
+    // blocks behind the head after which a block number is treated as final
+    #[serde(default = "default_finality_depth")]
+    pub finality_depth: u64,
+    // where responses are kept
+    #[serde(default)]
+    pub store: CacheStoreOptions
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FailoverOptions {
+    // attempts on an upstream before moving to the next one, `max_retries` caps the attempts of a request
+    #[serde(default = "default_attempts_per_upstream")]
+    pub attempts_per_upstream: u64,
+    // milliseconds a request can take over all of its attempts
+    #[serde(default)]
+    pub deadline: Option<u64>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum CacheRule {
+    // microseconds a response stays fresh
+    Ttl(u64),
+    Policy(CachePolicy)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CachePolicy {
+    Never,
+    Forever,
+    ForeverIfNotNull
+}
+
+fn default_attempts_per_upstream() -> u64 {
+    1
+}
+
+impl Default for FailoverOptions {
+    fn default() -> FailoverOptions {
+        FailoverOptions { attempts_per_upstream: default_attempts_per_upstream(), deadline: None }
+    }
+}
+
+fn default_finality_depth() -> u64 {
+    64
+}
+
+// toml has no 128 bit integers, every value that fits in a config fits in a u64
+fn deserialize_u128_from_u64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u128, D::Error> {
+    u64::deserialize(deserializer).map(u128::from)
+}
+
+impl Default for CacheOptions {
+    fn default() -> CacheOptions {
+        CacheOptions { cache_clear: 0, exclude_methods: vec![], methods: HashMap::new(), head_ttl: None, finality_depth: default_finality_depth(), store: CacheStoreOptions::default() }
+    }
+}
+
+impl CacheOptions {
+    pub fn rule(&self, method: &str, adapter: &dyn ChainAdapter) -> CacheRule {
+        if self.exclude_methods.iter().any(|excluded| excluded == method) {
+            return CacheRule::Policy(CachePolicy::Never);
+        }
+
+        self.methods.get(method).copied()
+            .or_else(|| adapter.default_rule(method))
+            .unwrap_or(CacheRule::Ttl(self.cache_ttl()))
+    }
+
+    // like `rule`, but the adapter may refine it from the params, `latest_block` is 0 while the head is unknown
+    pub fn rule_for(&self, request: &RpcRequest, adapter: &dyn ChainAdapter, latest_block: u64) -> CacheRule {
+        let rule = self.rule(&request.method, adapter);
+
+        if rule == CacheRule::Policy(CachePolicy::Never) || self.methods.contains_key(&request.method) || adapter.default_rule(&request.method).is_some() {
+            return rule;
+        }
+
+        adapter.request_rule(request, self, latest_block).unwrap_or(rule)
+    }
+
+    // how long reads relative to the head are served
+    pub fn head_ttl(&self) -> u64 {
+        self.head_ttl.unwrap_or_else(|| self.cache_ttl())
+    }
+
+    fn cache_ttl(&self) -> u64 {
+        self.cache_clear.min(u64::MAX as u128) as u64
+    }
+}
+
+impl CacheRule {
+    // false for rules under which nothing is ever served from the cache
+    pub fn is_cached(&self) -> bool {
+        !matches!(self, CacheRule::Policy(CachePolicy::Never) | CacheRule::Ttl(0))
+    }
+
+    pub fn is_fresh(&self, response: &Response) -> bool {
+        match self {
+            CacheRule::Ttl(ttl) => SystemTime::now().duration_since(response.start_time).map(|age| age.as_micros() <= *ttl as u128).unwrap_or(false),
+            CacheRule::Policy(CachePolicy::Never) => false,
+            CacheRule::Policy(_) => true
+        }
+    }
+
+    // error responses are never stored, they are usually temporary
+    pub fn should_store(&self, response: &Response) -> bool {
+        if response.error.is_some() {
+            return false;
+        }
+
+        match self {
+            CacheRule::Policy(CachePolicy::Never) => false,
+            CacheRule::Policy(CachePolicy::ForeverIfNotNull) => response.result.is_some(),
+            _ => true
+        }
+    }
+}
+
+impl FromStr for CacheRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<CacheRule, String> {
+        match s {
+            "never" => Ok(CacheRule::Policy(CachePolicy::Never)),
+            "forever" => Ok(CacheRule::Policy(CachePolicy::Forever)),
+            "forever_if_not_null" => Ok(CacheRule::Policy(CachePolicy::ForeverIfNotNull)),
+            ttl => ttl.parse::<u64>().map(CacheRule::Ttl)
+                .map_err(|_| format!("{} is not a cache rule, expected never, forever, forever_if_not_null or a ttl in microseconds", ttl))
+        }
+    }
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[value(rename_all = "snake_case")]
+pub enum Balancing {
+    // send to the fastest rpc until it hits max_connections or max_responses
+    #[default]
+    Latency,
+    WeightedRoundRobin,
+    LeastConnections,
+    LatencyWeightedRandom
+}
+
+impl Default for Quorum {
+    fn default() -> Quorum {
+        Quorum::Policy(QuorumPolicy::Majority)
+    }
+}
+
+impl Quorum {
+    // upstreams that have to agree out of `total`
+    pub fn required(&self, total: usize) -> usize {
+        match self {
+            Quorum::Count(count) => *count,
+            Quorum::Policy(QuorumPolicy::Majority) => total / 2 + 1,
+            Quorum::Policy(QuorumPolicy::All) => total
+        }
+    }
 }
 
 impl Default for Response {
     fn default() -> Response {
         Response {
             method: "".into(),
-            params: vec![],
-            result: Some(ResponseInnerData::Text("".into())),
+            params: Value::Null,
+            result: Some(Value::String("".into())),
             time_taken: 12,
             start_time: SystemTime::now(),
-            error: None
+            error: None,
+            body: None
         }
     }
 }
 
 impl Default for RpcResponse {
     fn default() -> RpcResponse {
-        RpcResponse { jsonrpc: "".into(), result: Some(ResponseInnerData::Text("".into())), id: NumberString::Text("".into()), error: None }
+        RpcResponse { jsonrpc: "".into(), result: Some(Value::String("".into())), id: NumberString::Text("".into()), error: None }
+    }
+}
+
+
+impl RpcRequest {
+    // params are serialized with sorted object keys, so equal arguments always give the same key
+    pub fn cache_key(&self) -> String {
+        format!("{}:{}", self.method, serde_json::to_string(&self.params).unwrap())
     }
 }
 
+impl ErrorCode {
+    // codes from the JSON-RPC 2.0 spec, svinge's own failures use the -32000 to -32099 server error range
+    pub fn code(&self) -> i64 {
+        match self {
+            ErrorCode::ParseError => -32700,
+            ErrorCode::InvalidRequest => -32600,
+            ErrorCode::InvalidParams => -32602,
+            ErrorCode::InternalError => -32603,
+            ErrorCode::UpstreamUnavailable => -32000,
+            ErrorCode::InvalidUpstreamResponse => -32001,
+            ErrorCode::QuorumNotReached => -32002,
+            ErrorCode::UpstreamTimeout => -32003
+        }
+    }
+}
+
+impl RpcError {
+    // turns the error into a JSON-RPC error response carrying the id of the failed request
+    pub fn into_response(self) -> RpcResponse {
+        let mut error = Map::new();
+        error.insert("code".into(), Value::from(self.code.code()));
+        error.insert("message".into(), Value::from(self.error));
+
+        if let Some(data) = self.data {
+            error.insert("data".into(), data);
+        }
+
+        RpcResponse { jsonrpc: "2.0".into(), result: None, id: self.id, error: Some(Value::Object(error)) }
+    }
+}
 
 impl Default for RpcError {
     fn default() -> RpcError {
-        RpcError { jsonrpc: "".into(), error: "".into(), method:"".into(), params: vec![], time_taken: 0, id: NumberString::Text("".into()) }
+        RpcError { code: ErrorCode::InternalError, jsonrpc: "".into(), error: "".into(), method:"".into(), params: Value::Null, time_taken: 0, id: NumberString::Text("".into()), data: None }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quorum_required() {
+        assert_eq!(Quorum::Policy(QuorumPolicy::Majority).required(1), 1);
+        assert_eq!(Quorum::Policy(QuorumPolicy::Majority).required(2), 2);
+        assert_eq!(Quorum::Policy(QuorumPolicy::Majority).required(3), 2);
+        assert_eq!(Quorum::Policy(QuorumPolicy::Majority).required(4), 3);
+        assert_eq!(Quorum::Policy(QuorumPolicy::All).required(3), 3);
+        assert_eq!(Quorum::Count(2).required(5), 2);
+    }
+
+    #[test]
+    fn quorum_parses_from_config() {
+        assert_eq!(serde_json::from_str::<Quorum>("\"majority\"").unwrap(), Quorum::Policy(QuorumPolicy::Majority));
+        assert_eq!(serde_json::from_str::<Quorum>("\"all\"").unwrap(), Quorum::Policy(QuorumPolicy::All));
+        assert_eq!(serde_json::from_str::<Quorum>("2").unwrap(), Quorum::Count(2));
+    }
+
+    #[test]
+    fn ids_round_trip_without_losing_precision() {
+        for id in ["2147483648", "4503599627370495", "18446744073709551615", "-9007199254740993", "1.5", "\"abc\"", "null"] {
+            let request = serde_json::from_str::<RpcRequest>(&format!("{{\"jsonrpc\":\"2.0\",\"method\":\"eth_chainId\",\"id\":{}}}", id)).unwrap();
+            let response = RpcResponse { jsonrpc: request.jsonrpc, id: request.id, result: Some(Value::Null), error: None };
+
+            assert_eq!(serde_json::to_string(&response).unwrap(), format!("{{\"jsonrpc\":\"2.0\",\"result\":null,\"id\":{}}}", id));
+        }
     }
-}
\ No newline at end of file
+}
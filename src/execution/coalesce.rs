@@ -0,0 +1,163 @@
+use std::{collections::HashMap, future::Future, sync::Mutex};
+use futures::channel::oneshot;
+
+use crate::common::types::{Response, RpcError};
+
+type Waiters = Vec<oneshot::Sender<Result<Response, RpcError>>>;
+
+// shares one upstream call between identical requests that are in flight at the same time
+#[derive(Debug, Default)]
+pub struct SingleFlight {
+    in_flight: Mutex<HashMap<String, Waiters>>
+}
+
+pub fn default_coalesce() -> bool {
+    true
+}
+
+// clears the key if the leading request is dropped before it finishes, its waiters then fetch on their own
+struct Leader<'a> {
+    flight: &'a SingleFlight,
+    key: Option<String>
+}
+
+impl Drop for Leader<'_> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.flight.in_flight.lock().unwrap().remove(&key);
+        }
+    }
+}
+
+impl SingleFlight {
+    // runs `fetch` unless a request with the same key is already running, in which case its result is awaited
+    pub async fn run<F, Fut>(&self, key: String, fetch: F) -> Result<Response, RpcError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Response, RpcError>>
+    {
+        let waiting = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+
+            match in_flight.get_mut(&key) {
+                Some(waiters) => {
+                    let (sender, receiver) = oneshot::channel();
+                    waiters.push(sender);
+                    Some(receiver)
+                },
+                None => {
+                    in_flight.insert(key.clone(), vec![]);
+                    None
+                }
+            }
+        };
+
+        if let Some(receiver) = waiting {
+            return match receiver.await {
+                Ok(result) => result,
+                Err(_) => fetch().await
+            };
+        }
+
+        let mut leader = Leader { flight: self, key: Some(key) };
+
+        let result = fetch().await;
+
+        let waiters = leader.key.take()
+            .and_then(|key| self.in_flight.lock().unwrap().remove(&key))
+            .unwrap_or_default();
+
+        for waiter in waiters {
+            let _ = waiter.send(result.clone());
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use futures::{FutureExt, future, poll};
+    use serde_json::json;
+
+    use super::*;
+
+    fn response(result: u64) -> Response {
+        Response { result: Some(json!(result)), ..Default::default() }
+    }
+
+    #[tokio::test]
+    async fn identical_requests_share_one_fetch() {
+        let flight = SingleFlight::default();
+        let calls = AtomicUsize::new(0);
+        let (sender, receiver) = oneshot::channel::<()>();
+        let receiver = receiver.shared();
+
+        let fetch = || {
+            calls.fetch_add(1, Ordering::SeqCst);
+            let receiver = receiver.clone();
+
+            async move {
+                let _ = receiver.await;
+                Ok(response(1))
+            }
+        };
+
+        let mut leader = flight.run("key".into(), fetch).boxed();
+        let mut follower = flight.run("key".into(), fetch).boxed();
+
+        assert!(poll!(&mut leader).is_pending());
+        assert!(poll!(&mut follower).is_pending());
+
+        sender.send(()).unwrap();
+
+        let (leader, follower) = future::join(leader, follower).await;
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(leader.unwrap().result, Some(json!(1)));
+        assert_eq!(follower.unwrap().result, Some(json!(1)));
+
+        // the key is free again once the leader is done
+        flight.run("key".into(), fetch).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn followers_fetch_themselves_when_the_leader_is_dropped() {
+        let flight = SingleFlight::default();
+
+        let mut leader = flight.run("key".into(), future::pending).boxed();
+        assert!(poll!(&mut leader).is_pending());
+
+        let mut follower = flight.run("key".into(), || async { Ok(response(2)) }).boxed();
+        assert!(poll!(&mut follower).is_pending());
+
+        drop(leader);
+
+        assert_eq!(follower.await.unwrap().result, Some(json!(2)));
+        assert!(flight.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn errors_reach_the_followers() {
+        let flight = SingleFlight::default();
+        let (sender, receiver) = oneshot::channel::<()>();
+
+        let mut leader = flight.run("key".into(), || async move {
+            let _ = receiver.await;
+            Err(RpcError { error: "boom".into(), ..Default::default() })
+        }).boxed();
+        assert!(poll!(&mut leader).is_pending());
+
+        let mut follower = flight.run("key".into(), || async { Ok(response(3)) }).boxed();
+        assert!(poll!(&mut follower).is_pending());
+
+        sender.send(()).unwrap();
+
+        let (leader, follower) = future::join(leader, follower).await;
+
+        assert_eq!(leader.unwrap_err().error, "boom");
+        assert_eq!(follower.unwrap_err().error, "boom");
+    }
+}
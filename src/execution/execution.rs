@@ -1,23 +1,124 @@
-use std::{collections::HashMap, time::SystemTime};
+use std::{collections::VecDeque, sync::{Arc, OnceLock, RwLock, atomic::{AtomicBool, AtomicU64, Ordering}}, time::Duration};
+use tokio::time::{Instant, timeout_at};
 use futures::{FutureExt, future::join_all};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use reqwest::Client;
 use log::{info, warn};
 
-use crate::{common::{types::*, helper::*}, extract_enum_value};
+use crate::{common::{types::*, helper::*, config::{ChainConfig, UpstreamConfig}, circuit_breaker::{CircuitBreaker, CircuitBreakerOptions, CircuitState}, health::{Health, HealthOptions, probe_upstream}, http::HttpOptions, latency::LatencyTracker, cache::{LruCache, ResponseCache}, state::{MemoryStore, StateStore}}, execution::{coalesce::{SingleFlight, default_coalesce}, selector::{self, UpstreamSelector}, subscriptions::SubscriptionHub}};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ExecutionClient {
     pub chain_type: Blockchain,
     pub chain_id: String,
-    pub rpc_urls: Vec<RPC>,
+    pub rpc_urls: RwLock<Vec<RPC>>,
     pub max_connections: u64,
     pub max_responses: u64,
     pub max_retries: u64,
+    #[serde(default = "default_history_size")]
+    pub history_size: usize,
     pub cache: CacheOptions,
-    pub response_results: HashMap<String, Response>
+    #[serde(default)]
+    pub quorum: Quorum,
+    #[serde(default)]
+    pub balancing: Balancing,
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerOptions,
+    #[serde(default)]
+    pub failover: FailoverOptions,
+    #[serde(default)]
+    pub health: HealthOptions,
+    #[serde(default)]
+    pub http: HttpOptions,
+    #[serde(skip, default = "default_selector")]
+    pub selector: Box<dyn UpstreamSelector>,
+    // identical requests in flight at the same time share one upstream call
+    #[serde(default = "default_coalesce")]
+    pub coalesce: bool,
+    #[serde(skip)]
+    pub in_flight: SingleFlight,
+    // uncached responses go back to the client as the upstream sent them
+    #[serde(default = "default_forward")]
+    pub forward: bool,
+    // started by `spawn_subscription_hub`, None until then
+    #[serde(skip)]
+    pub subscriptions: OnceLock<SubscriptionHub>,
+    #[serde(skip, default = "default_response_cache")]
+    pub response_cache: Box<dyn ResponseCache>,
+    // highest head of the last probe round, raised by head responses until the next one, 0 until one is seen
+    #[serde(skip)]
+    pub latest_block: AtomicU64,
+    #[serde(skip, default = "default_state")]
+    pub state: Arc<dyn StateStore>,
+    // set when something worth persisting changed since the last save
+    #[serde(skip)]
+    pub dirty: AtomicBool
+}
+
+// an upstream taken by `acquire_upstream`, dropping it gives the connection back, and the half open trial
+// too when the request went away before its outcome was recorded
+struct Acquired<'a> {
+    client: &'a ExecutionClient,
+    url: String,
+    trial: bool
+}
+
+impl Acquired<'_> {
+    // an outcome reached the circuit breaker, which already let go of the trial
+    fn settle(&mut self) {
+        self.trial = false;
+    }
+}
+
+impl Drop for Acquired<'_> {
+    fn drop(&mut self) {
+        if let Some(rpc) = self.client.rpc_urls.write().unwrap().iter_mut().find(|rpc| rpc.url == self.url) {
+            rpc.connections = rpc.connections.saturating_sub(1);
+
+            if self.trial {
+                rpc.circuit.release_trial();
+            }
+        }
+    }
+}
+
+fn default_selector() -> Box<dyn UpstreamSelector> {
+    Balancing::default().selector(u64::MAX, u64::MAX)
+}
+
+fn default_response_cache() -> Box<dyn ResponseCache> {
+    Box::new(LruCache::new(0, 0))
+}
+
+fn default_state() -> Arc<dyn StateStore> {
+    Arc::new(MemoryStore::default())
+}
+
+fn http_error(err: reqwest::Error) -> RpcError {
+    RpcError { code: ErrorCode::InternalError, error: format!("could not build the http client: {}", err), ..Default::default() }
+}
+
+// upstreams grouped by the response they returned, keyed by its serialization, the largest group first
+fn group_responses(responses: Vec<(String, Response)>) -> Vec<(String, Response, Vec<String>)> {
+    let mut groups: Vec<(String, Response, Vec<String>)> = vec![];
+
+    for (url, res) in responses {
+        let key = serde_json::to_string(&(&res.result, &res.error)).unwrap();
+
+        match groups.iter_mut().find(|(group_key, _, _)| *group_key == key) {
+            Some((_, _, group_urls)) => group_urls.push(url),
+            None => groups.push((key, res, vec![url]))
+        }
+    }
+
+    groups.sort_by_key(|(_, _, group_urls)| std::cmp::Reverse(group_urls.len()));
+
+    groups
 }
 
 impl ExecutionClient {
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
         chain_type: Blockchain,
         chain_id: String, 
@@ -26,213 +127,1148 @@ impl ExecutionClient {
         max_responses: u64, 
         max_retries: u64, 
         cache: CacheOptions, 
-        use_cached: bool
+        state: Arc<dyn StateStore>
     ) -> Result<ExecutionClient, RpcError> {
-        if use_cached {
-            info!("Looking if there is a cached file already...");
-            let text_result = std::fs::read_to_string(format!("/tmp/svinge/{}.json", chain_id.as_str()));
-
-            match text_result {
-                Ok(text) => {
-                    if text.len() > 0 {
-                        println!("{}", text);
-                        let config = serde_json::from_str::<ExecutionClient>(&text).unwrap();
-                        // println!("{:?}", config);
-                        return Ok(config);
-                    }
+        let chain = ChainConfig {
+            chain_id,
+            chain_type,
+            rpcs: rpc_urls.into_iter().map(UpstreamConfig::Url).collect(),
+            max_connections,
+            max_responses,
+            max_retries,
+            history_size: default_history_size(),
+            coalesce: default_coalesce(),
+            forward: default_forward(),
+            cache,
+            quorum: Quorum::default(),
+            balancing: Balancing::default(),
+            circuit_breaker: CircuitBreakerOptions::default(),
+            failover: FailoverOptions::default(),
+            health: HealthOptions::default(),
+            http: HttpOptions::default(),
+            route: None
+        };
+
+        ExecutionClient::from_config(&chain, state).await
+    }
+
+    // restores the chain's saved state when it has the same upstreams, probes them otherwise,
+    // either way every setting comes from `chain`
+    pub async fn from_config(chain: &ChainConfig, state: Arc<dyn StateStore>) -> Result<ExecutionClient, RpcError> {
+        let chain_id = &chain.chain_id;
+        let rpc_urls = chain.rpcs.iter().map(|rpc| rpc.url().clone()).collect::<Vec<_>>();
+
+        let response_cache = chain.cache.store.response_cache(chain_id).await.map_err(|err| RpcError {
+            code: ErrorCode::InternalError,
+            error: format!("could not open the response cache of chain {}: {}", chain_id, err),
+            ..Default::default()
+        })?;
+
+        let restored = match state.load(chain_id) {
+            Ok(Some(bytes)) => match serde_json::from_slice::<ExecutionClient>(&bytes) {
+                // the saved upstreams only stand in for the configured ones when they are the same
+                Ok(client) if client.has_upstreams(&rpc_urls) => {
+                    info!("Restored the state of chain {}", chain_id);
+                    Some(client)
+                },
+                Ok(_) => {
+                    warn!("Saved state of chain {} has other upstreams, probing them again", chain_id);
+                    None
                 },
-                Err(_) => warn!("Not found a cached file")
+                Err(err) => {
+                    warn!("Saved state of chain {} could not be read: {}", chain_id, err);
+                    None
+                }
+            },
+            Ok(None) => {
+                info!("No saved state for chain {}", chain_id);
+                None
+            },
+            Err(err) => {
+                warn!("Could not load the state of chain {}: {}", chain_id, err);
+                None
             }
+        };
+
+        let mut client = match restored {
+            Some(mut client) => {
+                client.response_cache = response_cache;
+                client.state = state;
+                client
+            },
+            None => ExecutionClient::probe(chain, &rpc_urls, response_cache, state).await?
+        };
 
+        client.configure(chain).map_err(http_error)?;
+
+        Ok(client)
+    }
+
+    // the saved state only keeps what was learned about the upstreams, a restored and a probed client
+    // both get their settings from `chain` here
+    fn configure(&mut self, chain: &ChainConfig) -> reqwest::Result<()> {
+        self.chain_type = chain.chain_type.clone();
+        self.max_connections = chain.max_connections;
+        self.max_responses = chain.max_responses;
+        self.max_retries = chain.max_retries;
+        self.history_size = chain.history_size;
+        self.cache = chain.cache.clone();
+        self.coalesce = chain.coalesce;
+        self.forward = chain.forward;
+        self.quorum = chain.quorum;
+        self.balancing = chain.balancing;
+        self.circuit_breaker = chain.circuit_breaker.clone();
+        self.failover = chain.failover.clone();
+        self.health = chain.health.clone();
+        self.http = chain.http.clone();
+        self.selector = chain.balancing.selector(chain.max_connections, chain.max_responses);
+        self.connect_upstreams()?;
+
+        for rpc in self.rpc_urls.get_mut().unwrap().iter_mut() {
+            if let Some(upstream) = chain.rpcs.iter().find(|upstream| *upstream.url() == rpc.url) {
+                rpc.weight = upstream.weight();
+                rpc.ws = upstream.ws().cloned();
+            }
         }
-        
-        let mut rpcs: Vec<RPC> = vec![];
-        let mut response_results: HashMap<String, Response> = HashMap::new();
 
-        let demo = &RpcRequest { jsonrpc: "2.0".into(), method: "eth_chainId".into(), params: vec![], id: NumberString::Number(1) };
+        Ok(())
+    }
+
+    // sends the identity call to every upstream a few times, to check they are on the chain and rank them by latency
+    async fn probe(chain: &ChainConfig, rpc_urls: &[String], response_cache: Box<dyn ResponseCache>, state: Arc<dyn StateStore>) -> Result<ExecutionClient, RpcError> {
+        let chain_id = chain.chain_id.clone();
+        let chain_type = chain.chain_type.clone();
+
+        let adapter = chain_type.adapter();
+        let demo = &RpcRequest { jsonrpc: "2.0".into(), method: adapter.identity_method().into(), params: Value::Array(vec![]), id: NumberString::Number(1.into()) };
+
+        let clients = rpc_urls.iter().map(|rpc| chain.http.client(rpc)).collect::<reqwest::Result<Vec<_>>>().map_err(http_error)?;
 
         let mut responses = vec![];
 
         info!("Requesting {} RPCs and recording their performances", rpc_urls.len());
 
         for i in 0..5 {
-            responses.append(&mut rpc_urls.iter().map(|rpc| {
+            responses.append(&mut rpc_urls.iter().zip(clients.iter()).map(|(rpc, client)| {
                 info!("Requesting {} RPC for ${} time", rpc, i);
-                request_and_record(rpc, demo).boxed()
+                request_and_record(client, rpc, demo).boxed()
             }).collect::<Vec<_>>());
         }
 
         let results = join_all(responses).await;
 
-        for (rpc, result) in rpc_urls.iter().zip(results) {
-            let res = result.unwrap();
+        let mut rpcs = rpc_urls.iter().zip(clients).map(|(rpc, client)| RPC {
+            url: rpc.clone(),
+            ws: None,
+            avg_response_time: 0,
+            latency: LatencyTracker::default(),
+            connections: 0,
+            weight: 1,
+            current_weight: 0,
+            circuit: CircuitBreaker::default(),
+            health: Health::default(),
+            response_counter: 0,
+            history: VecDeque::new(),
+            client
+        }).collect::<Vec<_>>();
 
-            let chain_id_en = &res.result;
+        // upstreams that answered for the chain, and ones that answered for another chain at least once
+        let mut verified = vec![false; rpc_urls.len()];
+        let mut mismatched = vec![false; rpc_urls.len()];
+        let mut cached = false;
 
-            let chain_id_enum = extract_enum_value!(chain_id_en, Some(ResponseInnerData::Text(chain_id_en)) => chain_id_en);
+        // results come round by round, so the i-th one belongs to the (i % len)-th RPC
+        for (i, result) in results.into_iter().enumerate() {
+            let idx = i % rpc_urls.len();
+            let rpc = &mut rpcs[idx];
 
-            let chain_id_from_hex = u64::from_str_radix(&chain_id_enum[2..], 16).unwrap().to_string();
+            let res = match result {
+                Ok(res) => res,
+                Err(err) => {
+                    rpc.record_sample(Sample::from_error(&err), chain.history_size);
+                    rpc.health.error.get_or_insert(err.error);
+                    continue;
+                }
+            };
 
-            if chain_id != chain_id_from_hex {
-                warn!("{} is not equal to RPCs response {}", chain_id, chain_id_from_hex);
-                return Err(RpcError { error: format!("{} is not equal to RPCs response {}", chain_id, chain_id_from_hex), jsonrpc: "2.0".into(), method: "eth_chainId".into(), params: vec![], id: NumberString::Text("1".into()), time_taken: 0 })
+            if let Err(error) = adapter.verify_identity(&chain_id, &res.result) {
+                rpc.health.error = Some(error);
+                mismatched[idx] = true;
+                continue;
             }
 
-            info!("All RPCs are of chain {}", chain_id_from_hex);
-            
-            let avg_time_taken = res.time_taken;
-            
-            response_results.insert("eth_chainId".into(), res.clone());
+            rpc.record_latency(res.time_taken);
+            rpc.record_sample(Sample::from_response(&res), chain.history_size);
+            verified[idx] = true;
+
+            if !cached {
+                response_cache.put(&demo.cache_key(), &res, None).await;
+                cached = true;
+            }
+        }
+
+        // an upstream that failed its probes is kept as unhealthy, the health checker brings it back once it
+        // answers for the chain
+        for (idx, rpc) in rpcs.iter_mut().enumerate() {
+            verified[idx] = verified[idx] && !mismatched[idx];
+
+            if verified[idx] {
+                rpc.health.error = None;
+            } else {
+                rpc.health.healthy = false;
+                warn!("{} failed its startup probes: {}", rpc.url, rpc.health.error.as_deref().unwrap_or("no answer"));
+            }
+        }
 
-            rpcs.push(RPC {
-                url: rpc.clone(),
-                avg_response_time: avg_time_taken,
-                connections: 0,
-                weight: 0,
-                response_counter: 0,
-                responses: vec![res]
+        if !verified.contains(&true) {
+            return Err(RpcError {
+                code: ErrorCode::UpstreamUnavailable,
+                error: format!("no upstream of chain {} passed its startup probes", chain_id),
+                jsonrpc: "2.0".into(),
+                method: demo.method.clone(),
+                params: demo.params.clone(),
+                ..Default::default()
             });
         }
+
+        info!("{} of {} RPCs are of chain {}", verified.iter().filter(|verified| **verified).count(), rpc_urls.len(), chain_id);
         
-        let mut new_config = ExecutionClient { 
+        let new_config = ExecutionClient { 
             chain_type, 
             chain_id, 
-            rpc_urls: rpcs, 
-            max_connections, 
-            max_responses, 
-            max_retries, 
-            cache, 
-            response_results 
+            rpc_urls: RwLock::new(rpcs), 
+            max_connections: chain.max_connections, 
+            max_responses: chain.max_responses, 
+            max_retries: chain.max_retries, 
+            history_size: chain.history_size,
+            cache: chain.cache.clone(), 
+            quorum: chain.quorum,
+            balancing: chain.balancing,
+            circuit_breaker: chain.circuit_breaker.clone(),
+            failover: chain.failover.clone(),
+            health: chain.health.clone(),
+            http: chain.http.clone(),
+            selector: chain.balancing.selector(chain.max_connections, chain.max_responses),
+            coalesce: chain.coalesce,
+            in_flight: SingleFlight::default(),
+            forward: chain.forward,
+            subscriptions: OnceLock::new(),
+            response_cache,
+            latest_block: AtomicU64::new(0),
+            state,
+            dirty: AtomicBool::new(true)
         };
 
         new_config.sort_rpcs();
 
-        new_config.update_db();
-
         Ok(new_config)
     }
 
-    fn update_db(&self) {
-        let path = format!("/tmp/svinge/{}.json", self.chain_id.as_str());
+    fn has_upstreams(&self, urls: &[String]) -> bool {
+        let rpc_urls = self.rpc_urls.read().unwrap();
+
+        rpc_urls.len() == urls.len() && rpc_urls.iter().all(|rpc| urls.contains(&rpc.url))
+    }
 
-        std::fs::write(path, serde_json::to_string_pretty(self).unwrap()).unwrap();
+    // only flags the client, the state writer saves it later
+    fn mark_dirty(&self) {
+        self.dirty.store(true, Ordering::Relaxed);
     }
 
-    fn swap_rpcs(&mut self, idx: usize) {
-        info!("Swapping RPCs");
+    // writes the client to its state store if it changed since the last save
+    pub fn save_state(&self) {
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return;
+        }
+
+        let result = serde_json::to_vec(self)
+            .map_err(std::io::Error::other)
+            .and_then(|bytes| self.state.save(&self.chain_id, &bytes));
+
+        if let Err(err) = result {
+            warn!("Could not save the state of chain {}: {}", self.chain_id, err);
+            self.mark_dirty();
+        }
+    }
+
+    // saves the client every `debounce` milliseconds when it changed, for as long as it is alive
+    pub fn spawn_state_writer(client: Arc<ExecutionClient>, debounce: u64) {
+        let client = Arc::downgrade(&client);
+
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_millis(debounce.max(1))).await;
+
+                let client = match client.upgrade() {
+                    Some(client) => client,
+                    None => break
+                };
+
+                // stores can block on disk
+                if tokio::task::spawn_blocking(move || client.save_state()).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    // replaces the selector picked by `balancing`, for routing that isn't built in
+    pub fn with_selector(mut self, selector: Box<dyn UpstreamSelector>) -> ExecutionClient {
+        self.selector = selector;
+        self
+    }
+
+    pub fn sort_rpcs(&self) {
+        selector::sort_rpcs(&mut self.rpc_urls.write().unwrap());
         
-        if idx > 0 {
-            let len = self.rpc_urls.len() - 1;
-            self.rpc_urls[0].response_counter = 0;
-    
-            //println!("{} {} {:?}", self.rpc_urls[0].url, self.rpc_urls[len].url, self.rpc_urls);
-            self.rpc_urls.swap(0, len);
-            //println!("{} {}", self.rpc_urls[0].url, self.rpc_urls[len].url);
-            if len < idx {
-                self.rpc_urls.swap(0, idx);
-                //println!("{} {}", self.rpc_urls[0].url, self.rpc_urls[idx].url);
+        self.mark_dirty();
+    }
+
+    // moves rpcs whose circuit is open or that are in `tried` behind the others, keeping their order,
+    // and returns how many can take requests, unhealthy or lagging rpcs are only used when no healthy one
+    // is left and down ranked ones come after those in sync
+    fn partition_available(&self, rpc_urls: &mut Vec<RPC>, tried: &[String]) -> usize {
+        let ranks = rpc_urls.iter_mut().map(|rpc| {
+            if tried.contains(&rpc.url) || !rpc.circuit.is_available(&self.circuit_breaker) {
+                3
+            } else if rpc.health.is_excluded(&self.health) {
+                2
+            } else if rpc.health.lagging {
+                1
+            } else {
+                0
             }
+        }).collect::<Vec<_>>();
+
+        let mut ordered = std::mem::take(rpc_urls).into_iter().zip(ranks).collect::<Vec<_>>();
+        ordered.sort_by_key(|(_, rank)| *rank);
+
+        let healthy = ordered.iter().filter(|(_, rank)| *rank < 2).count();
+        let available = ordered.iter().filter(|(_, rank)| *rank < 3).count();
+
+        *rpc_urls = ordered.into_iter().map(|(rpc, _)| rpc).collect();
+
+        if healthy > 0 {
+            healthy
+        } else {
+            available
         }
+    }
+
+    // gives every rpc its own pooled client built from `http`, so connections and TLS sessions are reused across requests
+    pub fn connect_upstreams(&mut self) -> reqwest::Result<()> {
+        for rpc in self.rpc_urls.get_mut().unwrap().iter_mut() {
+            rpc.client = self.http.client(&rpc.url)?;
+        }
+
+        Ok(())
+    }
 
+    fn upstream_client(&self, url: &str) -> Client {
+        self.rpc_urls.read().unwrap().iter().find(|rpc| rpc.url == url).map(|rpc| rpc.client.clone()).unwrap_or_default()
     }
 
-    pub fn sort_rpcs(&mut self) {
-        info!("Sorting RPCs");
-        let list = &mut self.rpc_urls;
+    // probes every rpc once, updating its latency and health
+    pub async fn check_health(&self) {
+        let urls = self.rpc_urls.read().unwrap().iter().map(|rpc| rpc.url.clone()).collect::<Vec<_>>();
 
-        list.sort_by(|a, b| a.avg_response_time.partial_cmp(&b.avg_response_time).unwrap());
-        
-        self.rpc_urls = list.to_vec();
+        let adapter = self.chain_type.adapter();
+        let probes = self.health.probes(adapter);
 
-        info!("Sorted RPCs, first -> {}, last -> {}", self.rpc_urls[0].url, self.rpc_urls.last().unwrap().url);
-        
-        self.update_db();
+        let probes = urls.iter().map(|url| {
+            probe_upstream(self.upstream_client(url), url, &self.chain_id, adapter, &probes).boxed()
+        }).collect::<Vec<_>>();
+
+        let probes = join_all(probes).await;
+
+        {
+            let mut rpc_urls = self.rpc_urls.write().unwrap();
+
+            // the highest head of this round only, so one upstream that once reported a head too high
+            // doesn't leave the others lagging for good, the identity check is part of every probe
+            let round_highest = probes.iter()
+                .filter(|probe| probe.health.healthy)
+                .filter_map(|probe| probe.health.latest_block)
+                .max();
+
+            if let Some(round_highest) = round_highest {
+                self.latest_block.store(round_highest, Ordering::Relaxed);
+            }
+
+            for (url, probe) in urls.iter().zip(probes) {
+
+                if let Some(rpc) = rpc_urls.iter_mut().find(|rpc| rpc.url == *url) {
+                    if !probe.health.healthy {
+                        warn!("{} is unhealthy: {:?}", url, probe.health.error);
+                    }
+
+                    if let Some(time_taken) = probe.time_taken {
+                        rpc.record_latency(time_taken);
+                    }
+
+                    rpc.health = probe.health;
+                }
+            }
+
+            let highest = self.latest_block.load(Ordering::Relaxed);
+
+            for rpc in rpc_urls.iter_mut() {
+                rpc.health.update_lag(highest, &self.health);
+
+                if rpc.health.lagging {
+                    warn!("{} is {} blocks behind", rpc.url, rpc.health.lag.unwrap_or(0));
+                }
+            }
+
+            self.selector.on_probe(&mut rpc_urls);
+        }
+
+        self.mark_dirty();
     }
 
-    pub async fn request(&mut self, request: RpcRequest) -> Result<RpcResponse, RpcError> {
-        info!("Received a request -> {:?}", request);
-        if (self.rpc_urls[0].connections > self.max_connections) || (self.rpc_urls[0].response_counter > self.max_responses) {
-            println!("{} {} {} {}", self.rpc_urls[0].connections, self.rpc_urls[0].response_counter, self.max_connections, self.max_responses);
-            self.swap_rpcs(1);
+    // probes the rpcs every `health.interval` milliseconds for as long as the client is alive
+    pub fn spawn_health_checker(client: Arc<ExecutionClient>) {
+        if client.health.interval == 0 {
+            return;
         }
-        
-        self.rpc_urls[0].connections = self.rpc_urls[0].connections + 1;        
-        self.update_db();
-        
-        let cached_result_exists = self.response_results.contains_key(&request.method);
-        // println!("{}", cached_result_exists);
-        if cached_result_exists {
-            let cached_result = self.response_results[&request.method].clone();
-            // println!("{:?} {} ", cached_result, SystemTime::now().duration_since(cached_result.start_time).unwrap().as_micros() <= self.cache.cache_clear);
-            
-            if SystemTime::now().duration_since(cached_result.start_time).unwrap().as_micros() <= self.cache.cache_clear {
-                println!("cached {}", self.rpc_urls[0].url);
-                return Ok(RpcResponse { jsonrpc: request.jsonrpc, id: request.id, result: cached_result.result, error: cached_result.error });
+
+        let client = Arc::downgrade(&client);
+
+        tokio::spawn(async move {
+            loop {
+                let interval = match client.upgrade() {
+                    Some(client) => {
+                        client.check_health().await;
+                        client.health.interval
+                    },
+                    None => break
+                };
+
+                tokio::time::sleep(Duration::from_millis(interval)).await;
             }
+        });
+    }
+
+    // starts the task eth_subscribe calls are multiplexed through, upstreams are tried in their current ranking
+    pub fn spawn_subscription_hub(client: Arc<ExecutionClient>) {
+        if !client.chain_type.adapter().supports_subscriptions() {
+            return;
         }
 
-        info!("not cached {}", self.rpc_urls[0].url);
+        let urls = client.rpc_urls.read().unwrap().iter().map(|rpc| rpc.ws_url()).collect::<Vec<_>>();
 
-        let mut res = Response::default();
+        if !urls.is_empty() {
+            let _ = client.subscriptions.set(SubscriptionHub::spawn(urls));
+        }
+    }
 
-        let mut err = RpcError::default();
+    fn record_success(&self, url: &str, res: &Response) {
+        if let Some(rpc) = self.rpc_urls.write().unwrap().iter_mut().find(|rpc| rpc.url == url) {
+            rpc.record_sample(Sample::from_response(res), self.history_size);
+            rpc.circuit.record_success(&self.circuit_breaker);
+        }
+    }
 
-        for _ in 0..self.max_retries {
-            let resx = request_and_record(&self.rpc_urls[0].url, &request).await;
+    // records a failed attempt against `url`, returns true when its circuit is no longer closed
+    fn record_failure(&self, url: &str, err: &RpcError) -> bool {
+        let mut rpc_urls = self.rpc_urls.write().unwrap();
 
-            match resx {
-                Ok(data) => {
-                    res = data;
-                    break;
+        match rpc_urls.iter_mut().find(|rpc| rpc.url == url) {
+            Some(rpc) => {
+                rpc.record_sample(Sample::from_error(err), self.history_size);
+                rpc.circuit.record_failure(&self.circuit_breaker);
+                !rpc.circuit.is_closed()
+            },
+            None => false
+        }
+    }
+
+    // raises the head between probe rounds, the next round sets it from the healthy upstreams again
+    fn record_block_number(&self, request: &RpcRequest, response: &Response) {
+        let adapter = self.chain_type.adapter();
+
+        if request.method != adapter.head_method() {
+            return;
+        }
+
+        if let Some(number) = adapter.parse_head(&response.result) {
+            self.latest_block.fetch_max(number, Ordering::Relaxed);
+        }
+    }
+
+    fn cache_rule(&self, request: &RpcRequest) -> CacheRule {
+        self.cache.rule_for(request, self.chain_type.adapter(), self.latest_block.load(Ordering::Relaxed))
+    }
+
+    async fn cached_response(&self, request: &RpcRequest) -> Option<Response> {
+        let rule = self.cache_rule(request);
+
+        if rule == CacheRule::Policy(CachePolicy::Never) {
+            return None;
+        }
+
+        let cached_result = self.response_cache.get(&request.cache_key()).await?;
+
+        if rule.is_fresh(&cached_result) {
+            return Some(cached_result);
+        }
+
+        None
+    }
+
+    // picks an upstream among the available ones that aren't in `tried` and takes a connection on it
+    fn acquire_upstream(&self, request: &RpcRequest, tried: &[String]) -> Option<Acquired<'_>> {
+        let upstream = {
+            let mut rpc_urls = self.rpc_urls.write().unwrap();
+
+            let available = self.partition_available(&mut rpc_urls, tried);
+
+            if available == 0 {
+                return None;
+            }
+
+            let idx = self.selector.select(&mut rpc_urls[..available], request);
+
+            let trial = rpc_urls[idx].circuit.state == CircuitState::HalfOpen;
+
+            rpc_urls[idx].connections += 1;
+            rpc_urls[idx].circuit.on_request();
+
+            Acquired { client: self, url: rpc_urls[idx].url.clone(), trial }
+        };
+
+        self.mark_dirty();
+
+        Some(upstream)
+    }
+
+    fn release_failed_upstream(&self, upstream: Acquired<'_>, err: &RpcError) {
+        let url = upstream.url.clone();
+
+        drop(upstream);
+
+        self.selector.on_failure(&mut self.rpc_urls.write().unwrap(), &url, err);
+    }
+
+    fn record_response(&self, mut upstream: Acquired<'_>, request: &RpcRequest, res: &Response) {
+        let url = upstream.url.clone();
+
+        let healthy = {
+            // the list can be reordered by other requests while this one is in flight
+            let mut rpc_urls = self.rpc_urls.write().unwrap();
+
+            let healthy = match rpc_urls.iter_mut().find(|rpc| rpc.url == url) {
+                Some(rpc) => {
+                    rpc.record_sample(Sample::from_response(res), self.history_size);
+                    rpc.response_counter += 1;
+                    rpc.record_latency(res.time_taken);
+                    rpc.circuit.record_success(&self.circuit_breaker);
+
+                    rpc.health.healthy
                 },
-                Err(error) => err = error
+                None => false
+            };
+
+            self.selector.on_success(&mut rpc_urls, &url, res);
+
+            healthy
+        };
+
+        upstream.settle();
+        drop(upstream);
+
+        // an unhealthy upstream may be on another chain or fork, its head can't be trusted
+        if healthy {
+            self.record_block_number(request, res);
+        }
+        self.mark_dirty();
+    }
+
+    async fn store_response(&self, request: &RpcRequest, res: &Response) {
+        let rule = self.cache_rule(request);
+
+        // a forwarded response is only parsed now, when there is a chance it gets stored
+        let parsed;
+        let res = match &res.body {
+            Some(body) if rule.is_cached() && res.error.is_none() => match forwarded_result(body) {
+                Ok(result) => {
+                    parsed = Response { result, ..res.clone() };
+                    &parsed
+                },
+                Err(_) => return
+            },
+            Some(_) => return,
+            None => res
+        };
+
+        if !rule.should_store(res) {
+            return;
+        }
+
+        let ttl = match rule {
+            CacheRule::Ttl(ttl) => Some(Duration::from_micros(ttl)),
+            CacheRule::Policy(_) => None
+        };
+
+        self.response_cache.put(&request.cache_key(), res, ttl).await;
+    }
+
+    async fn attempt(&self, url: &String, request: &RpcRequest, deadline: Option<Instant>, forward: bool) -> Result<Response, RpcError> {
+        let client = self.upstream_client(url);
+
+        let send = async {
+            if forward {
+                request_and_forward(&client, url, request).await
+            } else {
+                request_and_record(&client, url, request).await
             }
+        };
+
+        match deadline {
+            Some(deadline) => match timeout_at(deadline, send).await {
+                Ok(result) => result,
+                Err(_) => Err(upstream_error(ErrorCode::UpstreamTimeout, format!("{} timed out", url), request, 0))
+            },
+            None => send.await
+        }
+    }
+
+    // whether the request can take the forwarding path, the head call is always parsed to track the chain head
+    pub fn forwards(&self, request: &RpcRequest) -> bool {
+        self.forward && request.method != self.chain_type.adapter().head_method()
+    }
+
+    // like `request`, but on a cache miss the upstream's body goes back with only its id rewritten,
+    // its result is only parsed when the cache rule lets it be stored
+    pub async fn forward(&self, request: RpcRequest) -> Result<RpcResponseBody, RpcError> {
+        info!("Forwarding a request -> {:?}", request);
+
+        if let Some(cached_result) = self.cached_response(&request).await {
+            info!("cached {}", request.method);
+            return Ok(RpcResponseBody::Single(RpcResponse { jsonrpc: request.jsonrpc, id: request.id, result: cached_result.result, error: cached_result.error }));
         }
 
-        if res.method == Response::default().method {
-            self.swap_rpcs(1);
-            return Err(err);
+        let result = if self.coalesce && !self.chain_type.adapter().is_write_method(&request.method) {
+            // kept apart from parsed requests, whose waiters need a result
+            self.in_flight.run(format!("forward:{}", request.cache_key()), || self.fetch(&request, true)).await
+        } else {
+            self.fetch(&request, true).await
+        };
+
+        match result {
+            Ok(res) => Ok(RpcResponseBody::Forwarded(res.body.map(|body| forwarded_body(&body, &request.id)).unwrap_or_default())),
+            Err(err) => Err(RpcError { jsonrpc: request.jsonrpc, id: request.id, ..err })
+        }
+    }
+
+    pub async fn request(&self, request: RpcRequest) -> Result<RpcResponse, RpcError> {
+        info!("Received a request -> {:?}", request);
+
+        if let Some(cached_result) = self.cached_response(&request).await {
+            info!("cached {}", request.method);
+            return Ok(RpcResponse { jsonrpc: request.jsonrpc, id: request.id, result: cached_result.result, error: cached_result.error });
+        }
+
+        let result = if self.coalesce && !self.chain_type.adapter().is_write_method(&request.method) {
+            self.in_flight.run(request.cache_key(), || self.fetch(&request, false)).await
+        } else {
+            self.fetch(&request, false).await
+        };
+
+        match result {
+            Ok(res) => Ok(RpcResponse { jsonrpc: request.jsonrpc, id: request.id, result: res.result, error: res.error }),
+            // the error may come from an identical request with another id
+            Err(err) => Err(RpcError { jsonrpc: request.jsonrpc, id: request.id, ..err })
+        }
+    }
+
+    async fn fetch(&self, request: &RpcRequest, forward: bool) -> Result<Response, RpcError> {
+        let deadline = self.failover.deadline.map(|deadline| Instant::now() + Duration::from_millis(deadline));
+        let past_deadline = || deadline.map(|deadline| Instant::now() >= deadline).unwrap_or(false);
+
+        let mut tried: Vec<String> = vec![];
+        let mut attempts = 0;
+        let mut err = None;
+
+        // walks the upstreams in the order the selector ranks them, `max_retries` caps the attempts over all of them
+        while attempts < self.max_retries && !past_deadline() {
+            let mut upstream = match self.acquire_upstream(request, &tried) {
+                Some(upstream) => upstream,
+                // every available upstream was tried, start over
+                None if !tried.is_empty() => {
+                    tried.clear();
+                    continue;
+                },
+                None => break
+            };
+
+            let url = upstream.url.clone();
+
+            info!("not cached {}", url);
+
+            for _ in 0..self.failover.attempts_per_upstream.max(1) {
+                if attempts >= self.max_retries || past_deadline() {
+                    break;
+                }
+
+                attempts += 1;
+
+                match self.attempt(&url, request, deadline, forward).await {
+                    Ok(res) => {
+                        self.record_response(upstream, request, &res);
+                        self.store_response(request, &res).await;
+
+                        return Ok(res);
+                    },
+                    Err(error) => {
+                        warn!("{} failed {}: {}", url, request.method, error.error);
+
+                        // the request ran out of time, which says nothing about the upstream, it isn't held against
+                        // its circuit, and a half open trial goes back when the upstream is dropped unsettled
+                        if past_deadline() {
+                            err = Some(error);
+                            break;
+                        }
+
+                        // no point retrying an upstream whose circuit just opened
+                        let opened = self.record_failure(&url, &error);
+                        upstream.settle();
+                        err = Some(error);
+
+                        if opened {
+                            break;
+                        }
+                    }
+                }
+            }
+
+            let error = err.get_or_insert_with(|| self.exhausted(request, past_deadline()));
+            self.release_failed_upstream(upstream, error);
+
+            tried.push(url);
         }
 
-        self.rpc_urls[0].connections = self.rpc_urls[0].connections - 1;
+        Err(err.unwrap_or_else(|| {
+            if past_deadline() || attempts >= self.max_retries {
+                self.exhausted(request, past_deadline())
+            } else {
+                warn!("Every upstream of chain {} has an open circuit", self.chain_id);
+                upstream_error(ErrorCode::UpstreamUnavailable, "all upstreams are unavailable".into(), request, 0)
+            }
+        }))
+    }
 
-        let cloned_res = res.clone();
+    // why a request stopped before any upstream answered it
+    fn exhausted(&self, request: &RpcRequest, past_deadline: bool) -> RpcError {
+        if past_deadline {
+            upstream_error(ErrorCode::UpstreamUnavailable, format!("deadline of {} ms exceeded", self.failover.deadline.unwrap_or(0)), request, 0)
+        } else {
+            upstream_error(ErrorCode::UpstreamUnavailable, format!("no attempt left out of max_retries {}", self.max_retries), request, 0)
+        }
+    }
 
-        self.rpc_urls[0].responses.push(cloned_res.clone());
-        self.rpc_urls[0].response_counter = self.rpc_urls[0].response_counter + 1;
-        self.response_results.insert(request.method, cloned_res);
-        self.rpc_urls[0].avg_response_time = (self.rpc_urls[0].avg_response_time + res.time_taken) / self.rpc_urls[0].responses.len() as u128;
+    pub async fn request_batch(&self, requests: Vec<RpcRequest>) -> Vec<RpcResponse> {
+        let requests = requests.into_iter().map(|request| {
+            self.request(request).boxed()
+        }).collect::<Vec<_>>();
 
-        self.sort_rpcs();
+        join_all(requests).await.into_iter().map(|result| match result {
+            Ok(response) => response,
+            Err(err) => err.into_response()
+        }).collect()
+    }
+
+    pub async fn request_and_validate_batch(&self, requests: Vec<RpcRequest>) -> Vec<RpcResponse> {
+        let requests = requests.iter().map(|request| {
+            self.request_and_validate(request).boxed()
+        }).collect::<Vec<_>>();
 
-        Ok(RpcResponse { jsonrpc: request.jsonrpc, id: request.id, result: res.result, error: res.error })
+        join_all(requests).await.into_iter().map(|result| match result {
+            Ok(response) => response,
+            Err(err) => err.into_response()
+        }).collect()
     }
 
-    pub async fn request_and_validate(&mut self, request: &RpcRequest) -> Result<RpcResponse, RpcError> {
-        println!("started 123");
-        // let mut responses = vec![];
+    pub async fn request_and_validate(&self, request: &RpcRequest) -> Result<RpcResponse, RpcError> {
+        let (urls, closed) = {
+            let rpc_urls = self.rpc_urls.read().unwrap();
 
-        let requests = self.rpc_urls.iter().map(|rpc| {
-            request_and_record(&rpc.url, request).boxed()
+            let urls = rpc_urls.iter().map(|rpc| rpc.url.clone()).collect::<Vec<_>>();
+            let closed = rpc_urls.iter().filter(|rpc| rpc.circuit.is_closed()).map(|rpc| rpc.url.clone()).collect::<Vec<_>>();
+
+            (urls, closed)
+        };
+
+        let requests = closed.iter().map(|url| {
+            let client = self.upstream_client(url);
+
+            async move { request_and_record(&client, url, request).await }.boxed()
         }).collect::<Vec<_>>();
 
         let results = join_all(requests).await;
 
-        let mut response = RpcResponse::default();
+        let mut responses = vec![];
+        let mut failed = urls.iter().filter(|url| !closed.contains(url)).map(|url| {
+            serde_json::json!({ "url": url, "error": "circuit open" })
+        }).collect::<Vec<_>>();
+
+        for (url, result) in closed.iter().zip(results) {
+            match &result {
+                Ok(res) => self.record_success(url, res),
+                Err(err) => { self.record_failure(url, err); }
+            }
+
+            match result {
+                Ok(res) => responses.push((url.clone(), res)),
+                Err(err) => failed.push(serde_json::json!({ "url": url, "error": err.error }))
+            }
+        }
 
-        for (_, result) in self.rpc_urls.iter().zip(results) {
-            let res = result.unwrap();
+        let mut groups = group_responses(responses);
 
-            response = RpcResponse {
+        let required = self.quorum.required(urls.len());
+        let agreed = groups.first().map(|(_, _, group_urls)| group_urls.len()).unwrap_or(0);
+
+        let diverged = groups.iter().skip(1).flat_map(|(_, res, group_urls)| {
+            group_urls.iter().map(move |url| serde_json::json!({ "url": url, "result": res.result, "error": res.error }))
+        }).collect::<Vec<_>>();
+
+        // nothing to answer with when every upstream failed, whatever the quorum
+        if agreed == 0 || agreed < required {
+            warn!("Quorum of {} not reached for {}, {} upstreams agreed", required, request.method, agreed);
+
+            return Err(RpcError {
+                code: ErrorCode::QuorumNotReached,
+                error: format!("quorum of {} not reached, {} of {} upstreams agreed", required, agreed, urls.len()),
                 jsonrpc: request.jsonrpc.clone(),
+                method: request.method.clone(),
+                params: request.params.clone(),
                 id: request.id.clone(),
-                result: res.result,
-                error: res.error
-            }
+                time_taken: 0,
+                data: Some(serde_json::json!({
+                    "quorum": required,
+                    "agreed": groups.first().map(|(_, _, group_urls)| group_urls.clone()).unwrap_or_default(),
+                    "diverged": diverged,
+                    "failed": failed
+                }))
+            });
+        }
 
-            // todo: validate
+        if !diverged.is_empty() || !failed.is_empty() {
+            warn!("Upstreams diverged for {}: {:?} failed: {:?}", request.method, diverged, failed);
         }
 
-        Ok(response)
+        let (_, res, _) = groups.remove(0);
+
+        Ok(RpcResponse {
+            jsonrpc: request.jsonrpc.clone(),
+            id: request.id.clone(),
+            result: res.result,
+            error: res.error
+        })
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::common::stub::{self, Reply};
+
+    use super::*;
+
+    // a chain 5 config over `rpcs`, `settings` are more toml lines of it
+    fn chain(rpcs: &[&String], settings: &str) -> ChainConfig {
+        let rpcs = rpcs.iter().map(|rpc| format!("\"{}\"", rpc)).collect::<Vec<_>>().join(", ");
+
+        toml::from_str(&format!("chain_id = \"5\"\nchain_type = \"Evm\"\nrpcs = [{}]\nmax_connections = 10\nmax_responses = 10\nmax_retries = 3\n{}", rpcs, settings)).unwrap()
+    }
+
+    async fn chain_upstream() -> String {
+        stub::upstream(|request| stub::answer(request, "5", json!("0x1"))).await
+    }
+
+    fn response(result: Option<Value>, error: Option<Value>) -> Response {
+        Response { result, error, ..Default::default() }
+    }
+
+    #[test]
+    fn groups_identical_responses_largest_first() {
+        let groups = group_responses(vec![
+            ("a".into(), response(Some(json!("0x1")), None)),
+            ("b".into(), response(Some(json!("0x2")), None)),
+            ("c".into(), response(Some(json!("0x2")), None)),
+            ("d".into(), response(None, Some(json!({ "code": -32000, "message": "0x2" }))))
+        ]);
+
+        let urls = groups.iter().map(|(_, _, urls)| urls.clone()).collect::<Vec<_>>();
+
+        assert_eq!(urls, vec![vec!["b".to_string(), "c".into()], vec!["a".into()], vec!["d".into()]]);
+        assert_eq!(groups[0].1.result, Some(json!("0x2")));
+    }
+
+    #[test]
+    fn null_result_and_error_are_told_apart() {
+        let groups = group_responses(vec![
+            ("a".into(), response(None, None)),
+            ("b".into(), response(None, Some(json!({ "code": -32000, "message": "boom" })))),
+            ("c".into(), response(None, None))
+        ]);
+
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].2, vec!["a".to_string(), "c".into()]);
+    }
+
+    #[test]
+    fn no_responses_make_no_group() {
+        assert!(group_responses(vec![]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn restored_state_takes_the_configured_settings() {
+        let (a, b) = (chain_upstream().await, chain_upstream().await);
+        let state: Arc<dyn StateStore> = Arc::new(MemoryStore::default());
+
+        let client = ExecutionClient::from_config(&chain(&[&a, &b], ""), state.clone()).await.unwrap();
+        client.rpc_urls.write().unwrap().iter_mut().for_each(|rpc| rpc.response_counter = 7);
+        client.save_state();
+
+        let settings = "history_size = 50\nquorum = 2\nbalancing = \"least_connections\"\n[http]\nrequest_timeout = 1234\n[failover]\ndeadline = 900";
+        let client = ExecutionClient::from_config(&chain(&[&a, &b], settings), state.clone()).await.unwrap();
+
+        // what was learned about the upstreams is kept
+        assert!(client.rpc_urls.read().unwrap().iter().all(|rpc| rpc.response_counter == 7));
+        assert_eq!(client.history_size, 50);
+        assert_eq!(client.quorum, Quorum::Count(2));
+        assert_eq!(client.balancing, Balancing::LeastConnections);
+        assert_eq!(client.http.request_timeout, 1234);
+        assert_eq!(client.failover.deadline, Some(900));
+    }
+
+    #[tokio::test]
+    async fn probed_client_takes_the_configured_settings() {
+        let a = chain_upstream().await;
+
+        let client = ExecutionClient::from_config(&chain(&[&a], "coalesce = false\n[http]\nrequest_timeout = 1234"), Arc::new(MemoryStore::default())).await.unwrap();
+
+        assert!(!client.coalesce);
+        assert_eq!(client.http.request_timeout, 1234);
+    }
+
+    #[tokio::test]
+    async fn saved_state_of_other_upstreams_is_not_restored() {
+        let (a, b) = (chain_upstream().await, chain_upstream().await);
+        let state: Arc<dyn StateStore> = Arc::new(MemoryStore::default());
+
+        let client = ExecutionClient::from_config(&chain(&[&a, &b], ""), state.clone()).await.unwrap();
+        client.rpc_urls.write().unwrap().iter_mut().for_each(|rpc| rpc.response_counter = 7);
+        client.save_state();
+
+        assert!(client.has_upstreams(&[b.clone(), a.clone()]));
+        assert!(!client.has_upstreams(std::slice::from_ref(&a)));
+        assert!(!client.has_upstreams(&[a.clone(), b.clone(), "http://127.0.0.1:1".into()]));
+
+        let client = ExecutionClient::from_config(&chain(&[&a], ""), state).await.unwrap();
+
+        assert_eq!(client.rpc_urls.read().unwrap().len(), 1);
+        assert_eq!(client.rpc_urls.read().unwrap()[0].response_counter, 0);
+    }
+
+    #[tokio::test]
+    async fn upstreams_failing_startup_probes_are_kept_unhealthy() {
+        let good = chain_upstream().await;
+        let down = stub::upstream(|_| Reply::status(500)).await;
+        let other_chain = stub::upstream(|request| stub::answer(request, "1", json!("0x1"))).await;
+
+        let client = ExecutionClient::from_config(&chain(&[&good, &down, &other_chain], ""), Arc::new(MemoryStore::default())).await.unwrap();
+        let rpc_urls = client.rpc_urls.read().unwrap();
+        let health = |url: &String| rpc_urls.iter().find(|rpc| rpc.url == *url).unwrap().health.clone();
+
+        assert!(health(&good).healthy);
+        assert!(!health(&down).healthy);
+        assert!(!health(&other_chain).healthy);
+        assert!(health(&other_chain).error.unwrap().contains("Some(\"1\")"));
+    }
+
+    #[tokio::test]
+    async fn startup_fails_when_no_upstream_passes_its_probes() {
+        let down = stub::upstream(|_| Reply::status(500)).await;
+        let other_chain = stub::upstream(|request| stub::answer(request, "1", json!("0x1"))).await;
+
+        let err = ExecutionClient::from_config(&chain(&[&down, &other_chain], ""), Arc::new(MemoryStore::default())).await.unwrap_err();
+
+        assert_eq!(err.code, ErrorCode::UpstreamUnavailable);
+    }
+
+    // an upstream in sync at `head`
+    async fn head_upstream(head: Arc<AtomicU64>) -> String {
+        stub::upstream(move |request| match request["method"].as_str() {
+            Some("eth_blockNumber") => stub::answer(request, "5", json!(format!("0x{:x}", head.load(Ordering::Relaxed)))),
+            Some("eth_syncing") => stub::answer(request, "5", json!(false)),
+            _ => stub::answer(request, "5", json!("0x1"))
+        }).await
+    }
+
+    #[tokio::test]
+    async fn head_too_high_only_lasts_one_probe_round() {
+        let (a, b) = (head_upstream(Arc::new(AtomicU64::new(100))).await, head_upstream(Arc::new(AtomicU64::new(100))).await);
+        let bad_head = Arc::new(AtomicU64::new(1_000_000));
+        let bad = head_upstream(bad_head.clone()).await;
+
+        let client = ExecutionClient::from_config(&chain(&[&a, &b, &bad], ""), Arc::new(MemoryStore::default())).await.unwrap();
+        let lagging = |client: &ExecutionClient| client.rpc_urls.read().unwrap().iter().filter(|rpc| rpc.health.lagging).count();
+
+        client.check_health().await;
+
+        assert_eq!(client.latest_block.load(Ordering::Relaxed), 1_000_000);
+        assert_eq!(lagging(&client), 2);
+
+        // the bad upstream is back on the chain's head
+        bad_head.store(101, Ordering::Relaxed);
+        client.check_health().await;
+
+        assert_eq!(client.latest_block.load(Ordering::Relaxed), 101);
+        assert_eq!(lagging(&client), 0);
+    }
+
+    // an upstream on chain 5 answering every other method with `reply`, its calls to them are counted
+    async fn counted_upstream<F>(calls: Arc<AtomicU64>, reply: F) -> String
+    where
+        F: Fn(&Value) -> Reply + Send + Sync + 'static
+    {
+        stub::upstream(move |request| match request["method"].as_str() {
+            Some("eth_chainId") => stub::answer(request, "5", Value::Null),
+            _ => {
+                calls.fetch_add(1, Ordering::Relaxed);
+                reply(request)
+            }
+        }).await
+    }
+
+    // a second upstream whose identity answers are slower, so it ranks behind the first
+    async fn slow_to_probe(calls: Arc<AtomicU64>, reply: fn(&Value) -> Reply) -> String {
+        stub::upstream(move |request| match request["method"].as_str() {
+            Some("eth_chainId") => stub::answer(request, "5", Value::Null).after(Duration::from_millis(50)),
+            _ => {
+                calls.fetch_add(1, Ordering::Relaxed);
+                reply(request)
+            }
+        }).await
+    }
+
+    fn ok(request: &Value) -> Reply {
+        stub::answer(request, "5", json!("0x1"))
+    }
+
+    fn failing(_: &Value) -> Reply {
+        Reply::status(500)
+    }
+
+    fn eth_call() -> RpcRequest {
+        RpcRequest { jsonrpc: "2.0".into(), method: "eth_call".into(), params: json!([{ "to": "0x0" }, "latest"]), id: NumberString::Number(1.into()) }
+    }
+
+    fn counts(calls: &[&Arc<AtomicU64>]) -> Vec<u64> {
+        calls.iter().map(|calls| calls.load(Ordering::Relaxed)).collect()
+    }
+
+    #[tokio::test]
+    async fn failover_moves_on_to_the_next_upstream() {
+        let (a_calls, b_calls) = (Arc::new(AtomicU64::new(0)), Arc::new(AtomicU64::new(0)));
+        let a = counted_upstream(a_calls.clone(), failing).await;
+        let b = slow_to_probe(b_calls.clone(), ok).await;
+
+        let client = ExecutionClient::from_config(&chain(&[&a, &b], ""), Arc::new(MemoryStore::default())).await.unwrap();
+
+        assert_eq!(client.request(eth_call()).await.unwrap().result, Some(json!("0x1")));
+        assert_eq!(counts(&[&a_calls, &b_calls]), vec![1, 1]);
+    }
+
+    #[tokio::test]
+    async fn failover_retries_an_upstream_attempts_per_upstream_times() {
+        let (a_calls, b_calls) = (Arc::new(AtomicU64::new(0)), Arc::new(AtomicU64::new(0)));
+        let a = counted_upstream(a_calls.clone(), failing).await;
+        let b = slow_to_probe(b_calls.clone(), ok).await;
+
+        let client = ExecutionClient::from_config(&chain(&[&a, &b], "[failover]\nattempts_per_upstream = 2"), Arc::new(MemoryStore::default())).await.unwrap();
+
+        assert!(client.request(eth_call()).await.is_ok());
+        assert_eq!(counts(&[&a_calls, &b_calls]), vec![2, 1]);
+    }
+
+    #[tokio::test]
+    async fn failover_starts_over_until_max_retries() {
+        let (a_calls, b_calls) = (Arc::new(AtomicU64::new(0)), Arc::new(AtomicU64::new(0)));
+        let a = counted_upstream(a_calls.clone(), failing).await;
+        let b = slow_to_probe(b_calls.clone(), failing).await;
+
+        let mut config = chain(&[&a, &b], "");
+        config.max_retries = 5;
+        let client = ExecutionClient::from_config(&config, Arc::new(MemoryStore::default())).await.unwrap();
+
+        let err = client.request(eth_call()).await.unwrap_err();
+
+        // both, then both again once every one was tried, and a last one
+        let counts = counts(&[&a_calls, &b_calls]);
+        assert_eq!(counts.iter().sum::<u64>(), 5);
+        assert!(counts.iter().all(|count| *count >= 2));
+        assert_eq!(err.code, ErrorCode::UpstreamUnavailable);
+    }
+
+    #[tokio::test]
+    async fn deadline_stops_the_walk_without_opening_circuits() {
+        let calls = Arc::new(AtomicU64::new(0));
+        let slow = counted_upstream(calls.clone(), |request| ok(request).after(Duration::from_secs(2))).await;
+
+        let settings = "[failover]\ndeadline = 200\n[circuit_breaker]\nfailure_threshold = 1";
+        let client = ExecutionClient::from_config(&chain(&[&slow], settings), Arc::new(MemoryStore::default())).await.unwrap();
+
+        let started = Instant::now();
+        let err = client.request(eth_call()).await.unwrap_err();
+
+        assert_eq!(err.code, ErrorCode::UpstreamTimeout);
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(counts(&[&calls]), vec![1]);
+
+        let circuit = client.rpc_urls.read().unwrap()[0].circuit.clone();
+        assert!(circuit.is_closed());
+        assert_eq!(circuit.consecutive_failures, 0);
+        assert!(!circuit.trial_in_flight);
+    }
+
+    #[tokio::test]
+    async fn exhausted_deadline_sends_nothing() {
+        let calls = Arc::new(AtomicU64::new(0));
+        let a = counted_upstream(calls.clone(), ok).await;
+
+        let client = ExecutionClient::from_config(&chain(&[&a], "[failover]\ndeadline = 0"), Arc::new(MemoryStore::default())).await.unwrap();
+
+        let err = client.request(eth_call()).await.unwrap_err();
+
+        assert_eq!(err.code, ErrorCode::UpstreamUnavailable);
+        assert_eq!(err.error, "deadline of 0 ms exceeded");
+        assert_eq!(counts(&[&calls]), vec![0]);
+    }
+
+    #[tokio::test]
+    async fn slow_upstreams_time_out_and_the_next_one_answers() {
+        let (a_calls, b_calls) = (Arc::new(AtomicU64::new(0)), Arc::new(AtomicU64::new(0)));
+        let slow = counted_upstream(a_calls.clone(), |request| ok(request).after(Duration::from_secs(2))).await;
+        let b = slow_to_probe(b_calls.clone(), ok).await;
+
+        let settings = "[http]\nrequest_timeout = 200";
+
+        let mut config = chain(&[&slow], settings);
+        config.max_retries = 1;
+        let client = ExecutionClient::from_config(&config, Arc::new(MemoryStore::default())).await.unwrap();
+
+        assert_eq!(client.request(eth_call()).await.unwrap_err().code, ErrorCode::UpstreamTimeout);
+
+        let client = ExecutionClient::from_config(&chain(&[&slow, &b], settings), Arc::new(MemoryStore::default())).await.unwrap();
+        let started = Instant::now();
+
+        assert_eq!(client.request(eth_call()).await.unwrap().result, Some(json!("0x1")));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(counts(&[&a_calls, &b_calls]), vec![2, 1]);
+    }
+}
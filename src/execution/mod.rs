@@ -1 +1,5 @@
-pub mod execution;
\ No newline at end of file
+#[allow(clippy::module_inception)]
+pub mod execution;
+pub mod selector;
+pub mod coalesce;
+pub mod subscriptions;
\ No newline at end of file
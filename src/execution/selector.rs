@@ -0,0 +1,219 @@
+use std::fmt::Debug;
+use rand::Rng;
+
+use crate::common::types::{Balancing, RpcError, RpcRequest, Response, RPC};
+
+// decides which rpc serves a request, the execution client holds the rpc list lock while calling it,
+// so implementations can reorder the list or update fields such as `weight` and `current_weight`
+pub trait UpstreamSelector: Debug + Send + Sync {
+    // index of the rpc the request goes to
+    fn select(&self, rpc_urls: &mut [RPC], request: &RpcRequest) -> usize;
+
+    // called after `url` answered, its latency and counters are already updated
+    fn on_success(&self, _rpc_urls: &mut [RPC], _url: &str, _response: &Response) {}
+
+    // called when every retry against `url` failed
+    fn on_failure(&self, _rpc_urls: &mut [RPC], _url: &str, _error: &RpcError) {}
+
+    // called after the health checker probed every rpc and updated its latency and health
+    fn on_probe(&self, _rpc_urls: &mut [RPC]) {}
+}
+
+impl Balancing {
+    pub fn selector(&self, max_connections: u64, max_responses: u64) -> Box<dyn UpstreamSelector> {
+        match self {
+            Balancing::Latency => Box::new(LatencySelector { max_connections, max_responses }),
+            Balancing::WeightedRoundRobin => Box::new(WeightedRoundRobinSelector),
+            Balancing::LeastConnections => Box::new(LeastConnectionsSelector),
+            Balancing::LatencyWeightedRandom => Box::new(LatencyWeightedRandomSelector)
+        }
+    }
+}
+
+// keeps the list sorted by latency and sends to the fastest rpc until it hits
+// max_connections or max_responses, then rotates it out
+#[derive(Debug)]
+pub struct LatencySelector {
+    pub max_connections: u64,
+    pub max_responses: u64
+}
+
+impl UpstreamSelector for LatencySelector {
+    fn select(&self, rpc_urls: &mut [RPC], _request: &RpcRequest) -> usize {
+        if (rpc_urls[0].connections > self.max_connections) || (rpc_urls[0].response_counter > self.max_responses) {
+            swap_rpcs(rpc_urls, 1);
+        }
+
+        0
+    }
+
+    fn on_success(&self, rpc_urls: &mut [RPC], _url: &str, _response: &Response) {
+        sort_rpcs(rpc_urls);
+    }
+
+    fn on_failure(&self, rpc_urls: &mut [RPC], url: &str, _error: &RpcError) {
+        if rpc_urls[0].url == url {
+            swap_rpcs(rpc_urls, 1);
+        }
+    }
+
+    fn on_probe(&self, rpc_urls: &mut [RPC]) {
+        sort_rpcs(rpc_urls);
+    }
+}
+
+// smooth weighted round robin, spreads picks of heavier rpcs instead of sending them in bursts
+#[derive(Debug)]
+pub struct WeightedRoundRobinSelector;
+
+impl UpstreamSelector for WeightedRoundRobinSelector {
+    fn select(&self, rpc_urls: &mut [RPC], _request: &RpcRequest) -> usize {
+        let total = rpc_urls.iter().map(|rpc| rpc.weight as i64).sum::<i64>();
+
+        if total == 0 {
+            return 0;
+        }
+
+        for rpc in rpc_urls.iter_mut() {
+            rpc.current_weight += rpc.weight as i64;
+        }
+
+        let idx = rpc_urls.iter().enumerate()
+            .max_by_key(|(idx, rpc)| (rpc.current_weight, std::cmp::Reverse(*idx)))
+            .map(|(idx, _)| idx)
+            .unwrap_or(0);
+
+        rpc_urls[idx].current_weight -= total;
+
+        idx
+    }
+}
+
+#[derive(Debug)]
+pub struct LeastConnectionsSelector;
+
+impl UpstreamSelector for LeastConnectionsSelector {
+    fn select(&self, rpc_urls: &mut [RPC], _request: &RpcRequest) -> usize {
+        rpc_urls.iter().enumerate()
+            .min_by_key(|(_, rpc)| (rpc.connections, rpc.avg_response_time))
+            .map(|(idx, _)| idx)
+            .unwrap_or(0)
+    }
+}
+
+// faster rpcs are picked more often, but slow ones still get some traffic so their latency stays known,
+// speed counts their p95 along with the average
+#[derive(Debug)]
+pub struct LatencyWeightedRandomSelector;
+
+impl UpstreamSelector for LatencyWeightedRandomSelector {
+    fn select(&self, rpc_urls: &mut [RPC], _request: &RpcRequest) -> usize {
+        let weights = rpc_urls.iter().map(|rpc| 1.0 / (rpc.latency.tail_weighted() as f64 + 1.0)).collect::<Vec<_>>();
+        let total = weights.iter().sum::<f64>();
+
+        let mut pick = rand::thread_rng().gen_range(0.0..total);
+
+        for (idx, weight) in weights.iter().enumerate() {
+            if pick < *weight {
+                return idx;
+            }
+
+            pick -= weight;
+        }
+
+        weights.len() - 1
+    }
+}
+
+pub fn swap_rpcs(rpc_urls: &mut [RPC], idx: usize) {
+    log::info!("Swapping RPCs");
+    
+    if idx > 0 && rpc_urls.len() > 1 {
+        let len = rpc_urls.len() - 1;
+        rpc_urls[0].response_counter = 0;
+
+        rpc_urls.swap(0, len);
+        if len < idx {
+            rpc_urls.swap(0, idx);
+        }
+    }
+}
+
+pub fn sort_rpcs(rpc_urls: &mut [RPC]) {
+    log::info!("Sorting RPCs");
+
+    rpc_urls.sort_by(|a, b| a.avg_response_time.partial_cmp(&b.avg_response_time).unwrap());
+
+    if let (Some(first), Some(last)) = (rpc_urls.first(), rpc_urls.last()) {
+        log::info!("Sorted RPCs, first -> {}, last -> {}", first.url, last.url);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::common::types::NumberString;
+
+    fn rpc(url: &str, weight: u64, connections: u64, avg_response_time: u128) -> RPC {
+        RPC {
+            url: url.into(),
+            ws: None,
+            avg_response_time,
+            latency: Default::default(),
+            connections,
+            weight,
+            current_weight: 0,
+            circuit: Default::default(),
+            health: Default::default(),
+            response_counter: 0,
+            history: Default::default(),
+            client: Default::default()
+        }
+    }
+
+    fn request() -> RpcRequest {
+        RpcRequest { jsonrpc: "2.0".into(), method: "eth_call".into(), params: json!([]), id: NumberString::Number(1.into()) }
+    }
+
+    fn picks(selector: &dyn UpstreamSelector, rpc_urls: &mut [RPC], count: usize) -> Vec<String> {
+        (0..count).map(|_| {
+            let idx = selector.select(rpc_urls, &request());
+            rpc_urls[idx].url.clone()
+        }).collect()
+    }
+
+    #[test]
+    fn weighted_round_robin_spreads_heavier_picks() {
+        let mut rpc_urls = vec![rpc("a", 3, 0, 0), rpc("b", 1, 0, 0)];
+
+        assert_eq!(picks(&WeightedRoundRobinSelector, &mut rpc_urls, 8), vec!["a", "a", "b", "a", "a", "a", "b", "a"]);
+    }
+
+    #[test]
+    fn weighted_round_robin_never_picks_weight_zero() {
+        let mut rpc_urls = vec![rpc("a", 0, 0, 0), rpc("b", 1, 0, 0), rpc("c", 0, 0, 0), rpc("d", 2, 0, 0)];
+
+        let picks = picks(&WeightedRoundRobinSelector, &mut rpc_urls, 30);
+
+        assert!(picks.iter().all(|url| url == "b" || url == "d"));
+        assert_eq!(picks.iter().filter(|url| *url == "d").count(), 20);
+    }
+
+    #[test]
+    fn least_connections_breaks_ties_on_latency() {
+        let mut rpc_urls = vec![rpc("a", 1, 2, 10), rpc("b", 1, 1, 50), rpc("c", 1, 1, 20), rpc("d", 1, 3, 1)];
+
+        assert_eq!(picks(&LeastConnectionsSelector, &mut rpc_urls, 1), vec!["c"]);
+
+        rpc_urls[2].connections = 2;
+
+        assert_eq!(picks(&LeastConnectionsSelector, &mut rpc_urls, 1), vec!["b"]);
+    }
+
+    #[test]
+    fn sorting_an_empty_list_does_not_panic() {
+        sort_rpcs(&mut []);
+    }
+}
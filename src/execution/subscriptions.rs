@@ -0,0 +1,597 @@
+use std::{collections::HashMap, time::Duration};
+use futures::{SinkExt, StreamExt, channel::{mpsc, oneshot}, stream::{SplitSink, SplitStream}};
+use serde_json::{Value, json};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, connect_async, tungstenite::Message};
+use log::{info, warn};
+
+type Upstream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+// where the notifications of a client connection go, already serialized
+pub type Notifications = mpsc::Sender<String>;
+
+// notifications a client can fall behind by before it is disconnected
+pub const NOTIFICATION_BUFFER: usize = 1024;
+
+// milliseconds a client waits for the upstream to confirm its subscription
+const SUBSCRIBE_TIMEOUT: u64 = 10000;
+
+// milliseconds to open an upstream websocket before trying the next one
+const CONNECT_TIMEOUT: u64 = 5000;
+
+#[derive(Debug)]
+enum Command {
+    Subscribe { id: String, params: Vec<Value>, sink: Notifications, reply: oneshot::Sender<Result<String, String>> },
+    Unsubscribe { id: String, reply: oneshot::Sender<bool> }
+}
+
+// multiplexes the eth_subscribe calls of every client of a chain onto one upstream websocket
+#[derive(Debug, Clone)]
+pub struct SubscriptionHub {
+    commands: mpsc::UnboundedSender<Command>
+}
+
+impl SubscriptionHub {
+    // `urls` are the upstream websockets in the order they are tried, the hub stops with its last handle
+    pub fn spawn(urls: Vec<String>) -> SubscriptionHub {
+        let (commands, receiver) = mpsc::unbounded();
+
+        tokio::spawn(Hub::new(urls).run(receiver));
+
+        SubscriptionHub { commands }
+    }
+
+    // returns the id the client knows the subscription by
+    pub async fn subscribe(&self, params: Vec<Value>, sink: Notifications) -> Result<String, String> {
+        let (reply, receiver) = oneshot::channel();
+        let id = format!("0x{:032x}", rand::random::<u128>());
+
+        self.commands.unbounded_send(Command::Subscribe { id: id.clone(), params, sink, reply }).map_err(|_| "subscriptions are shut down".to_string())?;
+
+        match tokio::time::timeout(Duration::from_millis(SUBSCRIBE_TIMEOUT), receiver).await {
+            Ok(result) => result.unwrap_or_else(|_| Err("subscriptions are shut down".into())),
+            // the upstream may still confirm it later, the client must not get notifications it doesn't know of,
+            // not waiting for the hub to answer, it may be what is stuck
+            Err(_) => {
+                let (reply, _) = oneshot::channel();
+                let _ = self.commands.unbounded_send(Command::Unsubscribe { id, reply });
+
+                Err(format!("the upstream did not confirm the subscription within {} ms", SUBSCRIBE_TIMEOUT))
+            }
+        }
+    }
+
+    pub async fn unsubscribe(&self, id: &str) -> bool {
+        let (reply, receiver) = oneshot::channel();
+
+        if self.commands.unbounded_send(Command::Unsubscribe { id: id.into(), reply }).is_err() {
+            return false;
+        }
+
+        receiver.await.unwrap_or(false)
+    }
+}
+
+// clients subscribed with the same params, they share one upstream subscription
+struct Group {
+    params: Vec<Value>,
+    upstream_id: Option<String>,
+    subscribers: HashMap<String, Notifications>,
+    // clients waiting for the upstream to confirm the subscription
+    pending: Vec<(String, oneshot::Sender<Result<String, String>>)>
+}
+
+struct Hub {
+    urls: Vec<String>,
+    current: usize,
+    groups: HashMap<String, Group>,
+    // client subscription id -> key of its group
+    clients: HashMap<String, String>,
+    // upstream subscription id -> key of its group
+    upstream_ids: HashMap<String, String>,
+    // eth_subscribe calls sent upstream and not answered yet, by request id
+    in_flight: HashMap<u64, String>,
+    next_id: u64,
+    sink: Option<SplitSink<Upstream, Message>>
+}
+
+impl Hub {
+    fn new(urls: Vec<String>) -> Hub {
+        Hub {
+            urls,
+            current: 0,
+            groups: HashMap::new(),
+            clients: HashMap::new(),
+            upstream_ids: HashMap::new(),
+            in_flight: HashMap::new(),
+            next_id: 0,
+            sink: None
+        }
+    }
+
+    async fn run(mut self, mut commands: mpsc::UnboundedReceiver<Command>) {
+        let mut stream: Option<SplitStream<Upstream>> = None;
+
+        loop {
+            let reconnect = stream.is_none() && !self.groups.is_empty();
+
+            tokio::select! {
+                command = commands.next() => match command {
+                    Some(Command::Subscribe { id, params, sink, reply }) => {
+                        if stream.is_none() {
+                            stream = self.connect().await;
+                        }
+
+                        if stream.is_none() {
+                            let _ = reply.send(Err("no upstream websocket is available".into()));
+                            continue;
+                        }
+
+                        self.subscribe(id, params, sink, reply).await;
+                    },
+                    Some(Command::Unsubscribe { id, reply }) => {
+                        let _ = reply.send(self.unsubscribe(&id).await);
+                    },
+                    None => break
+                },
+                message = async { stream.as_mut().unwrap().next().await }, if stream.is_some() => match message {
+                    Some(Ok(Message::Text(text))) => self.on_message(&text).await,
+                    Some(Ok(_)) => {},
+                    Some(Err(_)) | None => {
+                        warn!("Lost the upstream websocket {}, failing over", self.urls[self.current]);
+
+                        self.disconnect();
+                        self.current = (self.current + 1) % self.urls.len();
+
+                        stream = self.connect().await;
+                    }
+                },
+                // every upstream was down on the last try, but clients are still subscribed
+                _ = tokio::time::sleep(Duration::from_secs(1)), if reconnect => {
+                    stream = self.connect().await;
+                }
+            }
+        }
+    }
+
+    // tries every upstream once, starting with the current one, and subscribes again to everything clients listen to
+    async fn connect(&mut self) -> Option<SplitStream<Upstream>> {
+        for _ in 0..self.urls.len() {
+            let url = &self.urls[self.current];
+
+            let connected = tokio::time::timeout(Duration::from_millis(CONNECT_TIMEOUT), connect_async(url.as_str())).await
+                .map_err(|_| format!("no answer within {} ms", CONNECT_TIMEOUT))
+                .and_then(|connected| connected.map_err(|err| err.to_string()));
+
+            match connected {
+                Ok((upstream, _)) => {
+                    info!("Connected to the upstream websocket {}", url);
+
+                    let (sink, stream) = upstream.split();
+                    self.sink = Some(sink);
+
+                    let keys = self.groups.keys().cloned().collect::<Vec<_>>();
+
+                    for key in keys {
+                        self.send_subscribe(&key).await;
+                    }
+
+                    return Some(stream);
+                },
+                Err(err) => {
+                    warn!("Could not connect to the upstream websocket {}: {}", url, err);
+                    self.current = (self.current + 1) % self.urls.len();
+                }
+            }
+        }
+
+        None
+    }
+
+    // upstream subscription ids die with the connection, the groups stay until they are subscribed again
+    fn disconnect(&mut self) {
+        self.sink = None;
+        self.upstream_ids.clear();
+        self.in_flight.clear();
+
+        for group in self.groups.values_mut() {
+            group.upstream_id = None;
+        }
+    }
+
+    async fn send(&mut self, method: &str, params: Value) -> Option<u64> {
+        self.next_id += 1;
+
+        let id = self.next_id;
+        let message = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }).to_string();
+
+        let sent = match self.sink.as_mut() {
+            Some(sink) => sink.send(Message::Text(message)).await.is_ok(),
+            None => false
+        };
+
+        // the read side notices the broken connection and fails over
+        if !sent {
+            self.sink = None;
+        }
+
+        sent.then_some(id)
+    }
+
+    async fn send_subscribe(&mut self, key: &str) {
+        let params = match self.groups.get(key) {
+            Some(group) => Value::Array(group.params.clone()),
+            None => return
+        };
+
+        if let Some(id) = self.send("eth_subscribe", params).await {
+            self.in_flight.insert(id, key.into());
+        }
+    }
+
+    async fn subscribe(&mut self, id: String, params: Vec<Value>, sink: Notifications, reply: oneshot::Sender<Result<String, String>>) {
+        let key = serde_json::to_string(&params).unwrap_or_default();
+
+        self.clients.insert(id.clone(), key.clone());
+
+        match self.groups.get_mut(&key) {
+            Some(group) => {
+                group.subscribers.insert(id.clone(), sink);
+
+                match group.upstream_id {
+                    Some(_) => { let _ = reply.send(Ok(id)); },
+                    None => group.pending.push((id, reply))
+                }
+            },
+            None => {
+                self.groups.insert(key.clone(), Group {
+                    params,
+                    upstream_id: None,
+                    subscribers: HashMap::from([(id.clone(), sink)]),
+                    pending: vec![(id, reply)]
+                });
+
+                self.send_subscribe(&key).await;
+            }
+        }
+    }
+
+    async fn unsubscribe(&mut self, id: &str) -> bool {
+        let key = match self.clients.remove(id) {
+            Some(key) => key,
+            None => return false
+        };
+
+        let empty = match self.groups.get_mut(&key) {
+            Some(group) => {
+                group.subscribers.remove(id);
+                group.subscribers.is_empty()
+            },
+            None => false
+        };
+
+        if empty {
+            self.drop_group(&key).await;
+        }
+
+        true
+    }
+
+    // the last subscriber left, the upstream subscription goes too
+    async fn drop_group(&mut self, key: &str) {
+        let group = match self.groups.remove(key) {
+            Some(group) => group,
+            None => return
+        };
+
+        for (id, _) in group.subscribers {
+            self.clients.remove(&id);
+        }
+
+        if let Some(upstream_id) = group.upstream_id {
+            self.upstream_ids.remove(&upstream_id);
+            self.send("eth_unsubscribe", json!([upstream_id])).await;
+        }
+    }
+
+    async fn on_message(&mut self, text: &str) {
+        let message = match serde_json::from_str::<Value>(text) {
+            Ok(message) => message,
+            Err(_) => return
+        };
+
+        if message["method"] == "eth_subscription" {
+            self.on_notification(message).await;
+            return;
+        }
+
+        let key = match message["id"].as_u64().and_then(|id| self.in_flight.remove(&id)) {
+            Some(key) => key,
+            None => return
+        };
+
+        // its clients left before the upstream confirmed it, or the group is already subscribed,
+        // nobody would get its notifications but the upstream keeps sending them
+        if self.groups.get(&key).is_none_or(|group| group.upstream_id.is_some()) {
+            if let Some(upstream_id) = message["result"].as_str().map(String::from) {
+                self.send("eth_unsubscribe", json!([upstream_id])).await;
+            }
+
+            return;
+        }
+
+        let group = match self.groups.get_mut(&key) {
+            Some(group) => group,
+            None => return
+        };
+
+        match message["result"].as_str() {
+            Some(upstream_id) => {
+                group.upstream_id = Some(upstream_id.into());
+                self.upstream_ids.insert(upstream_id.into(), key);
+
+                for (id, reply) in group.pending.drain(..) {
+                    let _ = reply.send(Ok(id));
+                }
+            },
+            None => {
+                let error = message["error"]["message"].as_str().unwrap_or("upstream refused the subscription").to_string();
+
+                warn!("Upstream refused subscription {}: {}", key, error);
+
+                for (_, reply) in group.pending.drain(..) {
+                    let _ = reply.send(Err(error.clone()));
+                }
+
+                self.drop_group(&key).await;
+            }
+        }
+    }
+
+    // hands the notification to every subscriber of the group under its own subscription id
+    async fn on_notification(&mut self, mut message: Value) {
+        let key = match message["params"]["subscription"].as_str().and_then(|id| self.upstream_ids.get(id)) {
+            Some(key) => key.clone(),
+            None => return
+        };
+
+        let group = match self.groups.get_mut(&key) {
+            Some(group) => group,
+            None => return
+        };
+
+        let mut gone = vec![];
+
+        for (id, sink) in group.subscribers.iter_mut() {
+            message["params"]["subscription"] = Value::String(id.clone());
+
+            match sink.try_send(message.to_string()) {
+                Ok(()) => {},
+                // closing the channel ends the connection, the client has to subscribe again
+                Err(err) if err.is_full() => {
+                    warn!("Subscriber {} fell {} notifications behind, disconnecting it", id, NOTIFICATION_BUFFER);
+                    sink.close_channel();
+                    gone.push(id.clone());
+                },
+                Err(_) => gone.push(id.clone())
+            }
+        }
+
+        // clients that went away without unsubscribing or fell behind
+        for id in gone {
+            self.unsubscribe(&id).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::accept_async;
+
+    use super::*;
+
+    // an upstream websocket answering eth_subscribe and eth_unsubscribe, its subscription ids start with `name`
+    struct WsUpstream {
+        url: String,
+        name: String,
+        // methods and params it was sent, in order
+        calls: Arc<Mutex<Vec<(String, Value)>>>,
+        // one per open connection, a close message drops the connection
+        connections: Arc<Mutex<Vec<mpsc::UnboundedSender<Message>>>>
+    }
+
+    impl WsUpstream {
+        async fn start(name: &str) -> WsUpstream {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("ws://{}", listener.local_addr().unwrap());
+            let calls = Arc::new(Mutex::new(vec![]));
+            let connections = Arc::new(Mutex::new(vec![]));
+
+            let (prefix, log, open) = (name.to_string(), calls.clone(), connections.clone());
+
+            tokio::spawn(async move {
+                let mut subscribed = 0;
+
+                while let Ok((socket, _)) = listener.accept().await {
+                    let (mut sink, mut stream) = accept_async(socket).await.unwrap().split();
+                    let (sender, mut receiver) = mpsc::unbounded::<Message>();
+                    open.lock().unwrap().push(sender);
+
+                    let (prefix, log) = (prefix.clone(), log.clone());
+                    let first = subscribed;
+                    subscribed += 1000;
+
+                    tokio::spawn(async move {
+                        let mut subscribed = first;
+
+                        loop {
+                            tokio::select! {
+                                message = stream.next() => match message {
+                                    Some(Ok(Message::Text(text))) => {
+                                        let request = serde_json::from_str::<Value>(&text).unwrap();
+                                        let method = request["method"].as_str().unwrap().to_string();
+                                        log.lock().unwrap().push((method.clone(), request["params"].clone()));
+
+                                        let result = match method.as_str() {
+                                            "eth_subscribe" => {
+                                                subscribed += 1;
+                                                json!(format!("{}{}", prefix, subscribed))
+                                            },
+                                            _ => json!(true)
+                                        };
+
+                                        let reply = json!({ "jsonrpc": "2.0", "id": request["id"], "result": result });
+                                        if sink.send(Message::Text(reply.to_string())).await.is_err() {
+                                            break;
+                                        }
+                                    },
+                                    Some(Ok(_)) => {},
+                                    _ => break
+                                },
+                                message = receiver.next() => match message {
+                                    Some(Message::Close(_)) | None => break,
+                                    Some(message) => if sink.send(message).await.is_err() {
+                                        break;
+                                    }
+                                }
+                            }
+                        }
+                    });
+                }
+            });
+
+            WsUpstream { url, name: name.into(), calls, connections }
+        }
+
+        fn notify(&self, upstream_id: &str, result: Value) {
+            let message = json!({ "jsonrpc": "2.0", "method": "eth_subscription", "params": { "subscription": upstream_id, "result": result } });
+
+            for connection in self.connections.lock().unwrap().iter() {
+                let _ = connection.unbounded_send(Message::Text(message.to_string()));
+            }
+        }
+
+        // drops every connection, like an upstream going down
+        fn kill(&self) {
+            for connection in self.connections.lock().unwrap().drain(..) {
+                let _ = connection.unbounded_send(Message::Close(None));
+            }
+        }
+
+        fn calls(&self, method: &str) -> Vec<Value> {
+            self.calls.lock().unwrap().iter().filter(|(called, _)| called == method).map(|(_, params)| params.clone()).collect()
+        }
+
+        // the id of the n-th subscription opened on the connection `connection` opened
+        fn subscription(&self, connection: u64, n: u64) -> String {
+            format!("{}{}", self.name, connection * 1000 + n)
+        }
+    }
+
+    async fn until(condition: impl Fn() -> bool) {
+        for _ in 0..500 {
+            if condition() {
+                return;
+            }
+
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        panic!("condition not met within 5 seconds");
+    }
+
+    async fn next(receiver: &mut mpsc::Receiver<String>) -> Value {
+        let message = tokio::time::timeout(Duration::from_secs(5), receiver.next()).await.unwrap().unwrap();
+
+        serde_json::from_str(&message).unwrap()
+    }
+
+    #[tokio::test]
+    async fn clients_share_an_upstream_subscription_under_their_own_ids() {
+        let upstream = WsUpstream::start("a").await;
+        let hub = SubscriptionHub::spawn(vec![upstream.url.clone()]);
+
+        let (first_sink, mut first) = mpsc::channel(NOTIFICATION_BUFFER);
+        let (second_sink, mut second) = mpsc::channel(NOTIFICATION_BUFFER);
+
+        let first_id = hub.subscribe(vec![json!("newHeads")], first_sink).await.unwrap();
+        let second_id = hub.subscribe(vec![json!("newHeads")], second_sink).await.unwrap();
+
+        assert_ne!(first_id, second_id);
+        assert_eq!(upstream.calls("eth_subscribe"), vec![json!(["newHeads"])]);
+
+        upstream.notify(&upstream.subscription(0, 1), json!({ "number": "0x1" }));
+
+        let (first, second) = (next(&mut first).await, next(&mut second).await);
+
+        assert_eq!(first["params"]["subscription"], json!(first_id));
+        assert_eq!(second["params"]["subscription"], json!(second_id));
+        assert_eq!(first["params"]["result"], second["params"]["result"]);
+
+        // the upstream subscription goes with its last client
+        assert!(hub.unsubscribe(&first_id).await);
+        assert!(upstream.calls("eth_unsubscribe").is_empty());
+        assert!(hub.unsubscribe(&second_id).await);
+        until(|| upstream.calls("eth_unsubscribe") == vec![json!([upstream.subscription(0, 1)])]).await;
+        assert!(!hub.unsubscribe(&second_id).await);
+    }
+
+    #[tokio::test]
+    async fn subscriptions_move_to_the_next_upstream_when_one_goes_down() {
+        let (a, b) = (WsUpstream::start("a").await, WsUpstream::start("b").await);
+        let hub = SubscriptionHub::spawn(vec![a.url.clone(), b.url.clone()]);
+
+        let (sink, mut receiver) = mpsc::channel(NOTIFICATION_BUFFER);
+        let id = hub.subscribe(vec![json!("newHeads")], sink).await.unwrap();
+
+        a.kill();
+        until(|| b.calls("eth_subscribe").len() == 1).await;
+
+        b.notify(&b.subscription(0, 1), json!({ "number": "0x2" }));
+
+        let notification = next(&mut receiver).await;
+
+        // the client keeps the id it was given
+        assert_eq!(notification["params"]["subscription"], json!(id));
+        assert_eq!(notification["params"]["result"]["number"], json!("0x2"));
+    }
+
+    #[tokio::test]
+    async fn slow_clients_are_disconnected() {
+        let upstream = WsUpstream::start("a").await;
+        let hub = SubscriptionHub::spawn(vec![upstream.url.clone()]);
+
+        let (sink, mut receiver) = mpsc::channel(NOTIFICATION_BUFFER);
+        hub.subscribe(vec![json!("newHeads")], sink).await.unwrap();
+
+        // the client reads nothing until it is dropped
+        for i in 0..NOTIFICATION_BUFFER + 10 {
+            upstream.notify(&upstream.subscription(0, 1), json!(i));
+        }
+
+        until(|| upstream.calls("eth_unsubscribe").len() == 1).await;
+
+        let mut received = 0;
+        while tokio::time::timeout(Duration::from_secs(5), receiver.next()).await.unwrap().is_some() {
+            received += 1;
+        }
+
+        assert!(received < NOTIFICATION_BUFFER + 10);
+    }
+
+    #[tokio::test]
+    async fn late_confirmations_are_unsubscribed() {
+        let upstream = WsUpstream::start("a").await;
+        let mut hub = Hub::new(vec![upstream.url.clone()]);
+        let _stream = hub.connect().await.unwrap();
+
+        // an eth_subscribe whose group was dropped after its clients gave up waiting
+        hub.in_flight.insert(7, "[\"newHeads\"]".into());
+        hub.on_message(&json!({ "jsonrpc": "2.0", "id": 7, "result": "0xlate" }).to_string()).await;
+
+        until(|| upstream.calls("eth_unsubscribe") == vec![json!(["0xlate"])]).await;
+        assert!(hub.upstream_ids.is_empty());
+    }
+}
@@ -1,7 +1,13 @@
+use std::path::PathBuf;
 use clap::{self, Parser, Subcommand};
 use svinge::{
-    common::types::{Blockchain, CacheOptions},
-    execution::execution::ExecutionClient,
+    common::{
+        circuit_breaker::CircuitBreakerOptions,
+        health::HealthOptions,
+        http::{HttpOptions, default_request_timeout},
+        config::{ChainConfig, Config, ServerConfig, UpstreamConfig},
+        types::{Balancing, Blockchain, CacheOptions, CacheRule, FailoverOptions, Quorum, default_history_size},
+    },
     server::server::run_server,
 };
 
@@ -23,19 +29,53 @@ enum Subcommands {
         #[arg(short = 'x', long = "max-responses")]
         max_responses: u64,
 
-        #[arg(short = 'i', long = "max-retries")]
+        #[arg(short = 'i', long = "max-retries", value_parser = clap::value_parser!(u64).range(1..))]
         max_retries: u64,
 
+        /// requests remembered per upstream
+        #[arg(short = 's', long = "history-size", default_value_t = default_history_size())]
+        history_size: usize,
+
+        /// send every request upstream, even when an identical one is in flight
+        #[arg(long = "no-coalesce")]
+        no_coalesce: bool,
+
+        /// parse every response, even the ones that aren't cached
+        #[arg(long = "no-forward")]
+        no_forward: bool,
+
         #[arg(short = 'a', long = "cache-clear")]
         cache_clear: u128,
 
         #[arg(short = 'e', long = "exclude-methods")]
         exclude_methods: Vec<String>,
+
+        /// per method cache rule as `method=rule`, rule is never, forever, forever_if_not_null or a ttl in microseconds
+        #[arg(short = 'u', long = "cache-rule", value_parser = parse_cache_rule)]
+        cache_rules: Vec<(String, CacheRule)>,
+
+        #[arg(short = 'b', long = "balancing", default_value = "latency")]
+        balancing: Balancing,
+
+        #[arg(short = 'n', long = "attempts-per-upstream", default_value_t = 1)]
+        attempts_per_upstream: u64,
+
+        /// milliseconds a request can take over all of its attempts
+        #[arg(short = 'd', long = "deadline")]
+        deadline: Option<u64>,
+
+        /// milliseconds a single attempt can take, 0 to wait forever
+        #[arg(long = "request-timeout", default_value_t = default_request_timeout())]
+        request_timeout: u64,
     },
     Public {
         #[arg(short = 'p', long = "with-public-providers")]
         with_public_provider: bool,
     },
+    Serve {
+        #[arg(short, long)]
+        config: PathBuf,
+    },
 }
 
 #[derive(Parser, Debug)]
@@ -46,52 +86,79 @@ struct Args {
     command: Option<Subcommands>,
 }
 
-async fn run_with_public_providers() {
-    let _pol_client = ExecutionClient::new(
-        Blockchain::Evm,
-        "80001".into(),
-        vec![
-            "https://rpc.ankr.com/polygon_mumbai/".into(),
-            "https://polygon-mumbai.g.alchemy.com/v2/Tv9MYE2mD4zn3ziBLd6S94HvLLjTocju/".into(),
-        ],
-        5,
-        5,
-        3,
-        CacheOptions {
-            cache_clear: 0,
-            exclude_methods: vec![],
+fn parse_cache_rule(s: &str) -> Result<(String, CacheRule), String> {
+    let (method, rule) = s.split_once('=').ok_or_else(|| format!("{} is not in the form method=rule", s))?;
+
+    Ok((method.into(), rule.parse::<CacheRule>()?))
+}
+
+// prints why svinge can't start and exits with a failure status
+fn exit_with(err: impl std::fmt::Display) -> ! {
+    eprintln!("{}", err);
+    std::process::exit(1)
+}
+
+fn public_providers() -> Vec<ChainConfig> {
+    vec![
+        ChainConfig {
+            chain_id: "80001".into(),
+            chain_type: Blockchain::Evm,
+            rpcs: vec![
+                UpstreamConfig::Url("https://rpc.ankr.com/polygon_mumbai/".into()),
+                UpstreamConfig::Url("https://polygon-mumbai.g.alchemy.com/v2/Tv9MYE2mD4zn3ziBLd6S94HvLLjTocju/".into()),
+            ],
+            max_connections: 5,
+            max_responses: 5,
+            max_retries: 3,
+            history_size: default_history_size(),
+            coalesce: true,
+            forward: true,
+            cache: CacheOptions {
+                cache_clear: 0,
+                exclude_methods: vec![],
+                ..Default::default()
+            },
+            quorum: Quorum::default(),
+            balancing: Balancing::default(),
+            circuit_breaker: CircuitBreakerOptions::default(),
+            failover: FailoverOptions::default(),
+            health: HealthOptions::default(),
+            http: HttpOptions::default(),
+            route: Some("pol".into()),
         },
-        false,
-    )
-    .await
-    .unwrap();
-
-    let _eth_client = ExecutionClient::new(
-        Blockchain::Evm,
-        "5".into(),
-        vec![
-            "https://rpc.ankr.com/eth_goerli/".into(),
-            "https://eth-goerli.g.alchemy.com/v2/Tv9MYE2mD4zn3ziBLd6S94HvLLjTocju/".into(),
-        ],
-        1,
-        1,
-        3,
-        CacheOptions {
-            cache_clear: 0,
-            exclude_methods: vec![],
+        ChainConfig {
+            chain_id: "5".into(),
+            chain_type: Blockchain::Evm,
+            rpcs: vec![
+                UpstreamConfig::Url("https://rpc.ankr.com/eth_goerli/".into()),
+                UpstreamConfig::Url("https://eth-goerli.g.alchemy.com/v2/Tv9MYE2mD4zn3ziBLd6S94HvLLjTocju/".into()),
+            ],
+            max_connections: 1,
+            max_responses: 1,
+            max_retries: 3,
+            history_size: default_history_size(),
+            coalesce: true,
+            forward: true,
+            cache: CacheOptions {
+                cache_clear: 0,
+                exclude_methods: vec![],
+                ..Default::default()
+            },
+            quorum: Quorum::default(),
+            balancing: Balancing::default(),
+            circuit_breaker: CircuitBreakerOptions::default(),
+            failover: FailoverOptions::default(),
+            health: HealthOptions::default(),
+            http: HttpOptions::default(),
+            route: Some("eth".into()),
         },
-        false,
-    )
-    .await
-    .unwrap();
+    ]
 }
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
 
-    unsafe { std::fs::create_dir("/tmp/svinge").unwrap_err_unchecked(); };
-
     let args = Args::parse();
 
     match args.command {
@@ -102,34 +169,69 @@ async fn main() {
             max_connections,
             max_responses,
             max_retries,
+            history_size,
+            no_coalesce,
+            no_forward,
             cache_clear,
             exclude_methods,
+            cache_rules,
+            balancing,
+            attempts_per_upstream,
+            deadline,
+            request_timeout,
         }) => {
-            let _client = ExecutionClient::new(
-                chain_type,
+            let chain = ChainConfig {
                 chain_id,
-                rpcs,
+                chain_type,
+                rpcs: rpcs.into_iter().map(UpstreamConfig::Url).collect(),
                 max_connections,
                 max_responses,
                 max_retries,
-                CacheOptions {
-                    cache_clear: cache_clear,
-                    exclude_methods: exclude_methods,
+                history_size,
+                coalesce: !no_coalesce,
+                forward: !no_forward,
+                cache: CacheOptions {
+                    cache_clear,
+                    exclude_methods,
+                    methods: cache_rules.into_iter().collect(),
+                    ..Default::default()
+                },
+                quorum: Quorum::default(),
+                balancing,
+                circuit_breaker: CircuitBreakerOptions::default(),
+                failover: FailoverOptions {
+                    attempts_per_upstream,
+                    deadline,
                 },
-                false,
-            )
-            .await
-            .unwrap();
+                health: HealthOptions::default(),
+                http: HttpOptions { request_timeout, ..Default::default() },
+                route: None,
+            };
 
-            run_server().await.unwrap();
+            let config = Config { server: ServerConfig::default(), chains: vec![chain] };
+
+            if let Err(err) = config.validate() {
+                exit_with(err);
+            }
+
+            if let Err(err) = run_server(config.server, config.chains).await {
+                exit_with(err);
+            }
         }
         Some(Subcommands::Public {
             with_public_provider,
         }) => {
             if with_public_provider {
-                run_with_public_providers().await;
+                if let Err(err) = run_server(ServerConfig::default(), public_providers()).await {
+                    exit_with(err);
+                }
+            }
+        }
+        Some(Subcommands::Serve { config }) => {
+            let config = Config::from_file(&config).unwrap_or_else(|err| exit_with(err));
 
-                run_server().await.unwrap();
+            if let Err(err) = run_server(config.server, config.chains).await {
+                exit_with(err);
             }
         }
         None => println!("default"),
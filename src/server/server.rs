@@ -1,7 +1,11 @@
 use actix_cors::Cors;
-use actix_web::{post, web, App, HttpResponse, HttpServer, Responder, body::BoxBody, http::header::ContentType, ResponseError, http::StatusCode};
-use crate::common::types::{RpcRequest, RpcResponse, CacheOptions, Blockchain};
-use crate::execution::execution::ExecutionClient;
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer, Responder, body::BoxBody, http::header::ContentType, ResponseError, http::StatusCode};
+use futures::{StreamExt, channel::mpsc};
+use serde_json::Value;
+use crate::common::types::{RpcRequest, RpcResponse, RpcResponseBody, RpcError, ErrorCode, NumberString};
+use crate::common::config::{ChainConfig, ServerConfig};
+use crate::common::helper::upstream_error;
+use crate::execution::{execution::ExecutionClient, subscriptions::{NOTIFICATION_BUFFER, Notifications}};
 use derive_more::{Display, Error};
 
 impl Responder for RpcResponse {
@@ -16,80 +20,428 @@ impl Responder for RpcResponse {
     }
 }
 
+impl Responder for RpcResponseBody {
+    type Body = BoxBody;
+
+    fn respond_to(self, _req: &actix_web::HttpRequest) -> HttpResponse<Self::Body> {
+        let body = match self {
+            RpcResponseBody::Forwarded(body) => body,
+            body => serde_json::to_string(&body).unwrap().into()
+        };
+
+        HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .body(body)
+    }
+}
+
 #[derive(Debug, Display, Error)]
 #[display(fmt = "{}", error_message)]
 struct ServerError {
     error_message: String,
+    response: RpcResponse,
     status: StatusCode
 }
 
-impl ResponseError for ServerError {}
+impl ServerError {
+    fn new(err: RpcError, status: StatusCode) -> ServerError {
+        ServerError { error_message: err.error.clone(), response: err.into_response(), status }
+    }
+}
 
-#[post("/pol")]
-async fn pol(req_body: web::Json<RpcRequest>) -> Result<impl Responder, ServerError> {
-    let req_result= ExecutionClient::new(Blockchain::Evm, "80001".into(), vec!["https://rpc.ankr.com/polygon_mumbai/".into(), "https://polygon-mumbai.g.alchemy.com/v2/Tv9MYE2mD4zn3ziBLd6S94HvLLjTocju/".into()], 1, 1, 5, CacheOptions { exclude_methods: vec![], cache_clear: 2000000 }, true).await;
+impl ResponseError for ServerError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
 
-    match req_result {
-        Ok(mut req) => {
-            let res = req.request(req_body.into_inner()).await;
-        
-            match res {
-                Ok(result) => Ok(result),
-                Err(err) => Err(ServerError { error_message: err.error, status: StatusCode::BAD_REQUEST })
-            }
-        },
-        Err(err) => Err(ServerError { error_message: err.error, status: StatusCode::BAD_REQUEST })
+    fn error_response(&self) -> HttpResponse<BoxBody> {
+        HttpResponse::build(self.status)
+            .content_type(ContentType::json())
+            .body(serde_json::to_string(&self.response).unwrap())
     }
 }
 
+fn invalid_request(error: String, id: NumberString) -> RpcError {
+    RpcError { code: ErrorCode::InvalidRequest, error, id, ..Default::default() }
+}
 
-#[post("/eth")]
-async fn eth(req_body: web::Json<RpcRequest>) -> Result<impl Responder, ServerError> {
-    println!("recevied");
-    let req_result= ExecutionClient::new(Blockchain::Evm, "5".into(), vec!["https://rpc.ankr.com/polygon_mumbai/".into(), "https://polygon-mumbai.g.alchemy.com/v2/Tv9MYE2mD4zn3ziBLd6S94HvLLjTocju/".into()], 1, 1, 5, CacheOptions { exclude_methods: vec![], cache_clear: 2000000 }, true).await;
+#[allow(clippy::result_large_err)]
+fn parse_request(value: Value) -> Result<RpcRequest, RpcError> {
+    let id = value.get("id")
+        .and_then(|id| serde_json::from_value::<NumberString>(id.clone()).ok())
+        .unwrap_or(NumberString::Null);
 
-    match req_result {
-        Ok(mut req) => {
-            let res = req.request(req_body.into_inner()).await;
-        
-            match res {
-                Ok(result) => Ok(result),
-                Err(err) => Err(ServerError { error_message: err.error, status: StatusCode::BAD_REQUEST })
-            }
-        },
-        Err(err) => Err(ServerError { error_message: err.error, status: StatusCode::BAD_REQUEST })
+    let request = serde_json::from_value::<RpcRequest>(value).map_err(|err| invalid_request(err.to_string(), id.clone()))?;
+
+    // params are positional or by name, when there are any
+    if !matches!(request.params, Value::Array(_) | Value::Object(_) | Value::Null) {
+        return Err(RpcError { code: ErrorCode::InvalidParams, error: "params must be an array or an object".into(), id, ..Default::default() });
+    }
+
+    Ok(request)
+}
+
+async fn execute(client: &ExecutionClient, request: RpcRequest, validate: bool) -> Result<RpcResponseBody, RpcError> {
+    if validate {
+        client.request_and_validate(&request).await.map(RpcResponseBody::Single)
+    } else if client.forwards(&request) {
+        client.forward(request).await
+    } else {
+        client.request(request).await.map(RpcResponseBody::Single)
     }
 }
 
-#[post("/ultra/eth")]
-async fn ultra_eth(req_body: web::Json<RpcRequest>) -> Result<impl Responder, ServerError> {
-    let req_result= ExecutionClient::new(Blockchain::Evm, "5".into(), vec!["https://rpc.ankr.com/polygon_mumbai/".into(), "https://polygon-mumbai.g.alchemy.com/v2/Tv9MYE2mD4zn3ziBLd6S94HvLLjTocju/".into()], 1, 1, 5, CacheOptions { exclude_methods: vec![], cache_clear: 2000000 }, true).await;
+async fn execute_batch(client: &ExecutionClient, items: Vec<Value>, validate: bool) -> Vec<RpcResponse> {
+    let mut requests = vec![];
+    let mut invalid = vec![];
+
+    // invalid items are answered in place, the rest are sent upstream
+    for item in items {
+        match parse_request(item) {
+            Ok(request) => {
+                invalid.push(None);
+                requests.push(request);
+            },
+            Err(err) => invalid.push(Some(err.into_response()))
+        }
+    }
+
+    let responses = if validate {
+        client.request_and_validate_batch(requests).await
+    } else {
+        client.request_batch(requests).await
+    };
+
+    let mut responses = responses.into_iter();
+
+    invalid.into_iter().map(|response| {
+        response.unwrap_or_else(|| responses.next().unwrap())
+    }).collect()
+}
+
+async fn handle(client: &ExecutionClient, server: &ServerConfig, body: &[u8], validate: bool) -> Result<RpcResponseBody, ServerError> {
+    let status = server.error_status();
+
+    let value = serde_json::from_slice::<Value>(body).map_err(|err| {
+        ServerError::new(RpcError { code: ErrorCode::ParseError, error: err.to_string(), id: NumberString::Null, ..Default::default() }, status)
+    })?;
+
+    match value {
+        Value::Array(items) if items.is_empty() => Err(ServerError::new(invalid_request("empty batch".into(), NumberString::Null), status)),
+        Value::Array(items) => Ok(RpcResponseBody::Batch(execute_batch(client, items, validate).await)),
+        value => {
+            let request = parse_request(value).map_err(|err| ServerError::new(err, status))?;
 
-    match req_result {
-        Ok(mut req) => {
-            let res = req.request_and_validate(&req_body.into_inner()).await;
-        
-            match res {
-                Ok(result) => Ok(result),
-                Err(err) => Err(ServerError { error_message: err.error, status: StatusCode::BAD_REQUEST })
+            execute(client, request, validate).await
+                .map_err(|err| ServerError::new(err, status))
+        }
+    }
+}
+
+async fn chain(client: web::Data<ExecutionClient>, server: web::Data<ServerConfig>, body: web::Bytes) -> Result<impl Responder, ServerError> {
+    handle(&client, &server, &body, false).await
+}
+
+async fn ultra_chain(client: web::Data<ExecutionClient>, server: web::Data<ServerConfig>, body: web::Bytes) -> Result<impl Responder, ServerError> {
+    handle(&client, &server, &body, true).await
+}
+
+// eth_subscribe and eth_unsubscribe go through the chain's subscription hub
+async fn subscription(client: &ExecutionClient, request: RpcRequest, sink: &Notifications, subscribed: &mut Vec<String>) -> Result<RpcResponse, RpcError> {
+    let hub = match client.subscriptions.get() {
+        Some(hub) => hub,
+        None => return Err(upstream_error(ErrorCode::UpstreamUnavailable, "subscriptions are not available".into(), &request, 0))
+    };
+
+    let params = match &request.params {
+        Value::Array(params) => params.clone(),
+        _ => vec![]
+    };
+
+    let result = if request.method == "eth_subscribe" {
+        let id = hub.subscribe(params, sink.clone()).await
+            .map_err(|err| upstream_error(ErrorCode::UpstreamUnavailable, err, &request, 0))?;
+
+        subscribed.push(id.clone());
+
+        Value::String(id)
+    } else {
+        // a connection can only cancel its own subscriptions
+        let id = params.first().and_then(|id| id.as_str()).unwrap_or_default();
+
+        match subscribed.iter().position(|subscription| subscription == id) {
+            Some(position) => {
+                subscribed.remove(position);
+                Value::Bool(hub.unsubscribe(id).await)
+            },
+            None => Value::Bool(false)
+        }
+    };
+
+    Ok(RpcResponse { jsonrpc: request.jsonrpc, id: request.id, result: Some(result), error: None })
+}
+
+async fn handle_ws_message(client: &ExecutionClient, server: &ServerConfig, body: &[u8], sink: &Notifications, subscribed: &mut Vec<String>) -> String {
+    let request = serde_json::from_slice::<Value>(body).ok()
+        .filter(|value| matches!(value["method"].as_str(), Some("eth_subscribe" | "eth_unsubscribe")))
+        .and_then(|value| parse_request(value).ok());
+
+    let response = match request {
+        Some(request) => subscription(client, request, sink, subscribed).await.unwrap_or_else(RpcError::into_response),
+        None => return match handle(client, server, body, false).await {
+            Ok(RpcResponseBody::Forwarded(body)) => String::from_utf8_lossy(&body).into_owned(),
+            Ok(body) => serde_json::to_string(&body).unwrap(),
+            Err(err) => serde_json::to_string(&err.response).unwrap()
+        }
+    };
+
+    serde_json::to_string(&response).unwrap()
+}
+
+async fn serve_ws(client: web::Data<ExecutionClient>, server: web::Data<ServerConfig>, mut session: actix_ws::Session, mut messages: actix_ws::MessageStream) {
+    let (sink, mut notifications) = mpsc::channel::<String>(NOTIFICATION_BUFFER);
+    let mut subscribed = vec![];
+    let mut reason = None;
+
+    loop {
+        tokio::select! {
+            message = messages.next() => match message {
+                Some(Ok(actix_ws::Message::Text(text))) => {
+                    let reply = handle_ws_message(&client, &server, text.as_bytes(), &sink, &mut subscribed).await;
+
+                    if session.text(reply).await.is_err() {
+                        break;
+                    }
+                },
+                Some(Ok(actix_ws::Message::Ping(bytes))) => {
+                    if session.pong(&bytes).await.is_err() {
+                        break;
+                    }
+                },
+                Some(Ok(actix_ws::Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+            notification = notifications.next() => match notification {
+                Some(notification) => {
+                    if session.text(notification).await.is_err() {
+                        break;
+                    }
+                },
+                // the hub closed the channel of a client that could not keep up
+                None => {
+                    reason = Some(actix_ws::CloseReason { code: actix_ws::CloseCode::Policy, description: Some("too many pending notifications".into()) });
+                    break;
+                }
             }
-        },
-        Err(err) => Err(ServerError { error_message: err.error, status: StatusCode::BAD_REQUEST })
+        }
+    }
+
+    if let Some(hub) = client.subscriptions.get() {
+        for id in subscribed {
+            hub.unsubscribe(&id).await;
+        }
+    }
+
+    let _ = session.close(reason).await;
+}
+
+async fn chain_ws(req: HttpRequest, payload: web::Payload, client: web::Data<ExecutionClient>, server: web::Data<ServerConfig>) -> Result<HttpResponse, actix_web::Error> {
+    let (response, session, messages) = actix_ws::handle(&req, payload)?;
+
+    actix_web::rt::spawn(serve_ws(client, server, session, messages));
+
+    Ok(response)
+}
+
+// JSON-RPC over POST and websockets on `route`, reads agreed on by a quorum on /ultra<route>
+fn chain_routes(config: &mut web::ServiceConfig, route: &str, client: &web::Data<ExecutionClient>) {
+    config
+        .service(web::resource(route).app_data(client.clone()).route(web::post().to(chain)).route(web::get().to(chain_ws)))
+        .service(web::resource(format!("/ultra{}", route)).app_data(client.clone()).route(web::post().to(ultra_chain)));
+}
+
+pub async fn run_server(server: ServerConfig, chains: Vec<ChainConfig>) -> std::io::Result<()> {
+    let state = server.state.store()?;
+    let mut clients = vec![];
+
+    for chain_config in chains.iter() {
+        let client = ExecutionClient::from_config(chain_config, state.clone()).await
+            .map_err(|err| std::io::Error::other(err.error))?;
+
+        clients.push((chain_config.route(), client));
     }
+
+    run_server_with_clients(server, clients).await
 }
 
-pub async fn run_server() -> std::io::Result<()> {
+// serves already built clients, each on its route and /ultra<route>, for clients with a custom selector,
+// websockets connect to the route itself
+pub async fn run_server_with_clients(server: ServerConfig, chains: Vec<(String, ExecutionClient)>) -> std::io::Result<()> {
+    let server = web::Data::new(server);
+    let mut clients = vec![];
+
+    // clients are shared by every worker, so counters and the cache live for the whole process
+    for (route, client) in chains {
+        println!("Serving chain {} on {} and /ultra{}", client.chain_id, route, route);
+
+        let client = web::Data::new(client);
+
+        ExecutionClient::spawn_health_checker(client.clone().into_inner());
+        ExecutionClient::spawn_subscription_hub(client.clone().into_inner());
+        ExecutionClient::spawn_state_writer(client.clone().into_inner(), server.state.debounce);
+
+        clients.push((route, client));
+    }
+
+    let saved = clients.iter().map(|(_, client)| client.clone()).collect::<Vec<_>>();
+
     println!("Running server on port {}🎉", 8080);
     
-    HttpServer::new(|| {
+    HttpServer::new(move || {
         let cors = Cors::default().allow_any_origin().allow_any_method().allow_any_header().supports_credentials();
-        App::new()
-            .wrap(cors)
-            .service(eth)
-            .service(pol)
-            .service(ultra_eth)
+        let mut app = App::new().wrap(cors).app_data(server.clone());
+
+        for (route, client) in clients.iter() {
+            app = app.configure(|config| chain_routes(config, route, client));
+        }
+
+        app
     })
     .bind("0.0.0.0:8080")?
     .run()
-    .await
-}
\ No newline at end of file
+    .await?;
+
+    // whatever changed since the last write
+    for client in saved {
+        client.save_state();
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use actix_web::{dev::ServiceResponse, test};
+    use serde_json::json;
+
+    use crate::common::{state::MemoryStore, stub::{self, Reply}};
+
+    use super::*;
+
+    // chain 5 over one stand-in upstream failing eth_call and answering the rest with 0x1
+    async fn chain_client() -> web::Data<ExecutionClient> {
+        let upstream = stub::upstream(|request| match request["method"].as_str() {
+            Some("eth_call") => Reply::status(500),
+            _ => stub::answer(request, "5", json!("0x1"))
+        }).await;
+
+        let chain = toml::from_str::<ChainConfig>(&format!("chain_id = \"5\"\nchain_type = \"Evm\"\nrpcs = [\"{}\"]\nmax_connections = 10\nmax_responses = 10\nmax_retries = 1", upstream)).unwrap();
+
+        web::Data::new(ExecutionClient::from_config(&chain, Arc::new(MemoryStore::default())).await.unwrap())
+    }
+
+    fn server(error_status: u16) -> web::Data<ServerConfig> {
+        web::Data::new(ServerConfig { error_status, ..Default::default() })
+    }
+
+    fn post(body: &str) -> test::TestRequest {
+        test::TestRequest::post().uri("/5").set_payload(body.to_string())
+    }
+
+    async fn read(response: ServiceResponse) -> (StatusCode, Value) {
+        let status = response.status();
+
+        (status, serde_json::from_slice(&test::read_body(response).await).unwrap())
+    }
+
+    #[actix_web::test]
+    async fn upstream_errors_echo_the_id_without_a_result() {
+        let client = chain_client().await;
+        let app = test::init_service(App::new().app_data(server(502)).configure(|config| chain_routes(config, "/5", &client))).await;
+
+        let (status, body) = read(test::call_service(&app, post(r#"{"jsonrpc":"2.0","method":"eth_call","params":[],"id":2147483648}"#).to_request()).await).await;
+
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert_eq!(body["id"], json!(2147483648u64));
+        assert!(body["error"]["code"].is_i64());
+        assert!(body.get("result").is_none());
+
+        let (status, body) = read(test::call_service(&app, post(r#"{"jsonrpc":"2.0","method":"eth_blockNumber","id":"a"}"#).to_request()).await).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "jsonrpc": "2.0", "result": "0x1", "id": "a" }));
+    }
+
+    #[actix_web::test]
+    async fn unparsable_bodies_get_a_parse_error_with_a_null_id() {
+        let client = chain_client().await;
+        let app = test::init_service(App::new().app_data(server(400)).configure(|config| chain_routes(config, "/5", &client))).await;
+
+        let (status, body) = read(test::call_service(&app, post(r#"{"jsonrpc":"2.0","method":"#).to_request()).await).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["code"], json!(-32700));
+        assert_eq!(body["id"], Value::Null);
+        assert!(body.get("result").is_none());
+    }
+
+    #[actix_web::test]
+    async fn invalid_requests_keep_their_id() {
+        let client = chain_client().await;
+        let app = test::init_service(App::new().app_data(server(200)).configure(|config| chain_routes(config, "/5", &client))).await;
+
+        let (status, body) = read(test::call_service(&app, post(r#"{"jsonrpc":"2.0","method":"eth_call","params":1,"id":1.5}"#).to_request()).await).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["error"]["code"], json!(-32602));
+        assert_eq!(body["id"], json!(1.5));
+
+        let (_, body) = read(test::call_service(&app, post(r#"{"jsonrpc":"2.0","id":7}"#).to_request()).await).await;
+
+        assert_eq!(body["error"]["code"], json!(-32600));
+        assert_eq!(body["id"], json!(7));
+    }
+
+    #[actix_web::test]
+    async fn batches_answer_every_item_in_order() {
+        let client = chain_client().await;
+        let app = test::init_service(App::new().app_data(server(502)).configure(|config| chain_routes(config, "/5", &client))).await;
+
+        let batch = r#"[
+            {"jsonrpc":"2.0","method":"eth_blockNumber","id":1},
+            {"jsonrpc":"2.0","id":2},
+            {"jsonrpc":"2.0","method":"eth_call","params":[],"id":3},
+            5,
+            {"jsonrpc":"2.0","method":"eth_getBalance","params":["0x0","latest"],"id":"4"}
+        ]"#;
+
+        let (status, body) = read(test::call_service(&app, post(batch).to_request()).await).await;
+
+        // a batch is always sent with 200, whatever its items say
+        assert_eq!(status, StatusCode::OK);
+
+        let items = body.as_array().unwrap();
+        assert_eq!(items.iter().map(|item| item["id"].clone()).collect::<Vec<_>>(), vec![json!(1), json!(2), json!(3), Value::Null, json!("4")]);
+
+        assert_eq!(items[0]["result"], json!("0x1"));
+        assert_eq!(items[1]["error"]["code"], json!(-32600));
+        // the upstream failed this one only
+        assert!(items[2]["error"]["code"].is_i64());
+        assert!(items[2].get("result").is_none());
+        assert_eq!(items[3]["error"]["code"], json!(-32600));
+        assert_eq!(items[4]["result"], json!("0x1"));
+    }
+
+    #[actix_web::test]
+    async fn empty_batches_are_invalid() {
+        let client = chain_client().await;
+        let app = test::init_service(App::new().app_data(server(400)).configure(|config| chain_routes(config, "/5", &client))).await;
+
+        let (status, body) = read(test::call_service(&app, post("[]").to_request()).await).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["code"], json!(-32600));
+        assert_eq!(body["id"], Value::Null);
+    }
+}